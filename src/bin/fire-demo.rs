@@ -0,0 +1,76 @@
+//! Demo that paints a color cube on every attached Fire and lights up grid
+//! pads while they're held down.  Fires can be plugged in and out while it
+//! runs, and get blanked when it's interrupted or terminated.
+
+extern crate controller_fire;
+extern crate tokio;
+
+use controller_fire::{ButtonState, ControllerEvent, FireManager};
+use std::time::Duration;
+use tokio::stream::StreamExt;
+
+/// Resolves once we've been asked to exit.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
+                return;
+            },
+            Err(e) => eprintln!("Can't listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut manager = FireManager::new();
+    manager.set_rescan_interval(Some(Duration::from_secs(1)));
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (id, event) = tokio::select! {
+            _ = &mut shutdown => break,
+            Some(item) = manager.next() => item,
+        };
+        for e in manager.take_scan_errors() {
+            eprintln!("Failed to attach controller: {}", e);
+        }
+
+        let c = match manager.get_mut(&id) {
+            Some(c) => c,
+            None => continue,
+        };
+        let result = match event {
+            ControllerEvent::Connected => {
+                c.set_color_cube();
+                // Pads get redrawn after every press, so let the writer batch
+                // that up.
+                c.set_frame_interval(Some(Duration::from_secs(1) / 60))
+                    .and_then(|_| c.update_leds())
+            },
+            ControllerEvent::GridButton(idx, _, _, ButtonState::Down, _) => {
+                c.set_led(idx, 0x7f, 0x7f, 0x7f).and_then(|_| c.update_leds())
+            },
+            ControllerEvent::GridButton(idx, _, _, ButtonState::Up, _) => {
+                c.set_led(idx, 0, 0, 0).and_then(|_| c.update_leds())
+            },
+            _ => Ok(())
+        };
+        if let Err(e) = result.and_then(|_| c.check_input()) {
+            eprintln!("Controller {}: {}", c.id(), e);
+        }
+    }
+
+    for (id, e) in manager.shutdown() {
+        eprintln!("Controller {} didn't shut down cleanly: {}", id, e);
+    }
+    Ok(())
+}
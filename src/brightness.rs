@@ -0,0 +1,218 @@
+//! Dimming the device without touching the colors the application set.
+//!
+//! A master level scales every pad and button LED, and any number of regions
+//! scale their part of the device on top of that.  Levels fade to new values
+//! over time; the writer steps the fades along with its frames and applies
+//! the result to what it sends, so the shadow state keeps the undimmed
+//! colors.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::output::PAD_COUNT;
+
+/// Part of the device a brightness multiplier applies to.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum LedRegion {
+    /// The pads in rows `rows` and columns `columns`, both 0-based.
+    Pads { rows: Range<u8>, columns: Range<u8> },
+    /// A single pad.
+    Pad(u8),
+    /// The button LEDs, the track row LEDs and the mode indicators.
+    Buttons,
+}
+
+impl LedRegion {
+    /// The whole grid.
+    pub fn grid() -> Self {
+        LedRegion::Pads { rows: 0..4, columns: 0..16 }
+    }
+
+    fn contains_pad(&self, index: u8) -> bool {
+        match self {
+            LedRegion::Pads { rows, columns } => {
+                rows.contains(&(index / 16)) && columns.contains(&(index % 16))
+            },
+            LedRegion::Pad(pad) => *pad == index,
+            LedRegion::Buttons => false,
+        }
+    }
+}
+
+/// A level on its way from one value to another.
+#[derive(Copy, Clone, Debug)]
+struct Fade {
+    from: f64,
+    to: f64,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Fade {
+    fn steady(level: f64) -> Self {
+        Fade { from: level, to: level, start: Instant::now(), duration: Duration::from_secs(0) }
+    }
+
+    fn level_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.duration {
+            self.to
+        } else {
+            let t = elapsed.as_secs_f64() / self.duration.as_secs_f64();
+            self.from + (self.to - self.from) * t
+        }
+    }
+
+    fn is_fading(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start) < self.duration
+    }
+
+    /// Heads for `to` from wherever the level is at `now`.
+    fn retarget(&mut self, to: f64, duration: Duration, now: Instant) {
+        *self = Fade { from: self.level_at(now), to, start: now, duration };
+    }
+}
+
+/// Multipliers regions can go up to, for emphasizing them on a dimmed device.
+pub const MAX_REGION_LEVEL: f64 = 4.0;
+
+/// The master level and the region multipliers, as the writer keeps them.
+pub(crate) struct Brightness {
+    master: Fade,
+    regions: HashMap<LedRegion, Fade>,
+}
+
+impl Brightness {
+    pub(crate) fn new() -> Self {
+        Brightness { master: Fade::steady(1.0), regions: HashMap::new() }
+    }
+
+    /// Fades the master level to `level`, clamped to 0.0..=1.0.
+    pub(crate) fn set_master(&mut self, level: f64, fade: Duration, now: Instant) {
+        self.master.retarget(clamp_level(level, 1.0), fade, now);
+    }
+
+    /// Fades the multiplier for `region` to `level`, clamped to
+    /// 0.0..=`MAX_REGION_LEVEL`.  Regions at 1.0 are forgotten once there.
+    pub(crate) fn set_region(&mut self, region: LedRegion, level: f64, fade: Duration,
+                             now: Instant) {
+        let level = clamp_level(level, MAX_REGION_LEVEL);
+        self.regions.entry(region).or_insert_with(|| Fade::steady(1.0))
+            .retarget(level, fade, now);
+    }
+
+    pub(crate) fn is_fading(&self, now: Instant) -> bool {
+        self.master.is_fading(now) || self.regions.values().any(|fade| fade.is_fading(now))
+    }
+
+    /// Drops the regions that settled back at 1.0.
+    pub(crate) fn prune(&mut self, now: Instant) {
+        self.regions.retain(|_, fade| fade.is_fading(now) || fade.to != 1.0);
+    }
+
+    /// The level of every pad at `now`.
+    pub(crate) fn pad_levels(&self, now: Instant) -> [f64; PAD_COUNT] {
+        let mut levels = [self.master.level_at(now); PAD_COUNT];
+        for (region, fade) in &self.regions {
+            let level = fade.level_at(now);
+            for (i, pad) in levels.iter_mut().enumerate() {
+                if region.contains_pad(i as u8) {
+                    *pad *= level;
+                }
+            }
+        }
+        levels
+    }
+
+    /// The level of the button LEDs at `now`.
+    pub(crate) fn button_level(&self, now: Instant) -> f64 {
+        let region = self.regions.get(&LedRegion::Buttons).map_or(1.0, |fade| fade.level_at(now));
+        self.master.level_at(now) * region
+    }
+}
+
+/// Scales a 7-bit pad color, saturating at 0x7f.
+pub(crate) fn scale_pad([r, g, b]: [u8; 3], level: f64) -> [u8; 3] {
+    let scale = |c: u8| (c as f64 * level).round().min(127.0) as u8;
+    [scale(r), scale(g), scale(b)]
+}
+
+pub(crate) fn clamp_level(level: f64, max: f64) -> f64 {
+    if level.is_nan() { 0.0 } else { level.clamp(0.0, max) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn fades_run_from_start_to_end() {
+        let start = Instant::now();
+        let mut fade = Fade::steady(1.0);
+        fade.retarget(0.0, SECOND, start);
+        assert!(close(fade.level_at(start), 1.0));
+        assert!(close(fade.level_at(start + SECOND / 4), 0.75));
+        assert!(fade.is_fading(start + SECOND / 2));
+        assert!(close(fade.level_at(start + SECOND), 0.0));
+        assert!(close(fade.level_at(start + 2 * SECOND), 0.0));
+        assert!(!fade.is_fading(start + SECOND));
+        // Times before the start read as the start.
+        assert!(close(fade.level_at(start - SECOND), 1.0));
+    }
+
+    #[test]
+    fn retargeting_starts_from_the_current_level() {
+        let start = Instant::now();
+        let mut fade = Fade::steady(0.0);
+        fade.retarget(1.0, SECOND, start);
+        fade.retarget(0.0, SECOND, start + SECOND / 2);
+        assert!(close(fade.level_at(start + SECOND / 2), 0.5));
+        assert!(close(fade.level_at(start + SECOND), 0.25));
+
+        // Without a fade the level jumps.
+        fade.retarget(0.8, Duration::from_secs(0), start + SECOND);
+        assert!(close(fade.level_at(start + SECOND), 0.8));
+    }
+
+    #[test]
+    fn regions_multiply_on_top_of_the_master() {
+        let now = Instant::now();
+        let none = Duration::from_secs(0);
+        let mut brightness = Brightness::new();
+        brightness.set_master(0.5, none, now);
+        brightness.set_region(LedRegion::Pads { rows: 0..1, columns: 0..16 }, 0.5, none, now);
+        brightness.set_region(LedRegion::Pad(0), 3.0, none, now);
+        brightness.set_region(LedRegion::Buttons, 10.0, none, now);
+
+        let levels = brightness.pad_levels(now);
+        assert!(close(levels[0], 0.75));
+        assert!(close(levels[15], 0.25));
+        assert!(close(levels[16], 0.5));
+        // The buttons' multiplier was clamped.
+        assert!(close(brightness.button_level(now), 0.5 * MAX_REGION_LEVEL));
+        assert_eq!(scale_pad([0x7f, 0x40, 0], MAX_REGION_LEVEL), [0x7f, 0x7f, 0]);
+    }
+
+    #[test]
+    fn regions_back_at_one_are_pruned() {
+        let now = Instant::now();
+        let mut brightness = Brightness::new();
+        brightness.set_region(LedRegion::grid(), 0.5, Duration::from_secs(0), now);
+        brightness.set_region(LedRegion::Buttons, 0.5, Duration::from_secs(0), now);
+        brightness.set_region(LedRegion::grid(), 1.0, SECOND, now);
+        brightness.prune(now);
+        assert_eq!(brightness.regions.len(), 2);
+
+        // Only once the fade back is over.
+        brightness.prune(now + SECOND);
+        assert_eq!(brightness.regions.keys().collect::<Vec<_>>(), vec![&LedRegion::Buttons]);
+        assert!(!brightness.is_fading(now + SECOND));
+    }
+}
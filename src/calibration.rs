@@ -0,0 +1,462 @@
+//! Making different devices show the same pad values the same way.
+//!
+//! LEDs vary from batch to batch, so two Fires given the same values can
+//! disagree on what white looks like.  A `Calibration` corrects the values
+//! on their way to the device: the writer applies it to every pad it sends,
+//! while the shadow state keeps the values as the application set them.
+//!
+//! `CalibrationStore` keeps a profile per device id, and
+//! `DiscoveryOptions::with_calibrations` has each controller pick up its own
+//! on attach.  `CalibrationSession` helps find the values in the first place.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::color::linear_to_fire;
+use crate::command::{ControllerCommand, PadColor};
+use crate::device::DeviceId;
+use crate::error::{FireError, Result};
+use crate::input::{ButtonState, ControllerButton, ControllerEvent, ControllerKnob};
+use crate::output::{self, ButtonLedState, TRACK_ROWS};
+use crate::writer::OutputHandle;
+
+const IDENTITY: [[f64; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// Corrections applied to the 7-bit (r, g, b) pad values, in this order:
+/// the white-balance matrix mixes the channels, each channel is raised to
+/// the power of its curve exponent, and then multiplied by its gain.  All of
+/// it happens on levels scaled to 0.0..=1.0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Calibration {
+    /// Row `i` holds how much of each input channel goes into output channel
+    /// `i`.
+    pub white_balance: [[f64; 3]; 3],
+    /// Exponent per channel.  Above 1.0 darkens the midtones.
+    pub curve: [f64; 3],
+    /// Multiplier per channel.
+    pub gain: [f64; 3],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            white_balance: IDENTITY,
+            curve: [1.0; 3],
+            gain: [1.0; 3],
+        }
+    }
+}
+
+impl Calibration {
+    /// The calibration that changes nothing.
+    pub fn new() -> Self {
+        Calibration::default()
+    }
+
+    pub fn with_white_balance(mut self, matrix: [[f64; 3]; 3]) -> Self {
+        self.white_balance = matrix;
+        self
+    }
+
+    pub fn with_curve(mut self, curve: [f64; 3]) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    pub fn with_gain(mut self, gain: [f64; 3]) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Calibration::default()
+    }
+
+    /// Corrects a 7-bit (r, g, b) pad value.  Black stays black.
+    pub fn apply(&self, rgb: [u8; 3]) -> [u8; 3] {
+        if self.is_identity() || rgb == [0; 3] {
+            return rgb;
+        }
+        let level = [rgb[0] as f64 / 127.0, rgb[1] as f64 / 127.0, rgb[2] as f64 / 127.0];
+        let mut out = [0; 3];
+        for (i, out) in out.iter_mut().enumerate() {
+            let mixed: f64 = (0..3).map(|j| self.white_balance[i][j] * level[j]).sum();
+            let curved = mixed.max(0.0).powf(self.curve[i]);
+            *out = linear_to_fire(curved * self.gain[i]);
+        }
+        out
+    }
+}
+
+/// Formats as `gain=r,g,b curve=r,g,b white_balance=rr,rg,rb,gr,gg,gb,br,bg,bb`,
+/// which `FromStr` reads back.
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |values: &[f64]| {
+            values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+        };
+        let matrix: Vec<f64> = self.white_balance.iter().flatten().copied().collect();
+        write!(f, "gain={} curve={} white_balance={}",
+               list(&self.gain), list(&self.curve), list(&matrix))
+    }
+}
+
+impl FromStr for Calibration {
+    type Err = FireError;
+
+    /// Parts that are left out keep their identity values.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |what: &str| {
+            FireError::InvalidArgument(format!("bad calibration {:?}: {}", s, what))
+        };
+        let mut calibration = Calibration::new();
+        for part in s.split_whitespace() {
+            let (key, values) = match part.find('=') {
+                Some(i) => (&part[..i], &part[i + 1..]),
+                None => return Err(invalid(part)),
+            };
+            let values = values.split(',').map(|v| v.parse::<f64>())
+                .collect::<std::result::Result<Vec<f64>, _>>()
+                .map_err(|_| invalid(part))?;
+            match (key, values.len()) {
+                ("gain", 3) => calibration.gain.copy_from_slice(&values),
+                ("curve", 3) => calibration.curve.copy_from_slice(&values),
+                ("white_balance", 9) => {
+                    for (row, chunk) in calibration.white_balance.iter_mut().zip(values.chunks(3)) {
+                        row.copy_from_slice(chunk);
+                    }
+                },
+                _ => return Err(invalid(part)),
+            }
+        }
+        Ok(calibration)
+    }
+}
+
+/// Calibration profiles by device id.
+///
+/// Formats as one line per device, the id followed by its calibration, which
+/// `FromStr` reads back, so that profiles can be kept in a file.  Blank lines
+/// and lines starting with '#' are skipped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CalibrationStore {
+    profiles: HashMap<DeviceId, Calibration>,
+}
+
+impl CalibrationStore {
+    pub fn new() -> Self {
+        CalibrationStore::default()
+    }
+
+    pub fn insert(&mut self, id: DeviceId, calibration: Calibration) {
+        self.profiles.insert(id, calibration);
+    }
+
+    pub fn remove(&mut self, id: &DeviceId) -> Option<Calibration> {
+        self.profiles.remove(id)
+    }
+
+    pub fn get(&self, id: &DeviceId) -> Option<&Calibration> {
+        self.profiles.get(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&DeviceId, &Calibration)> {
+        self.profiles.iter()
+    }
+}
+
+impl fmt::Display for CalibrationStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Sorted so that saving the same profiles gives the same file.
+        let mut ids: Vec<&DeviceId> = self.profiles.keys().collect();
+        ids.sort();
+        for id in ids {
+            writeln!(f, "{} {}", id, self.profiles[id])?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CalibrationStore {
+    type Err = FireError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut store = CalibrationStore::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, calibration) = match line.find(char::is_whitespace) {
+                Some(i) => (&line[..i], &line[i..]),
+                None => (line, ""),
+            };
+            store.insert(id.parse()?, calibration.parse()?);
+        }
+        Ok(store)
+    }
+}
+
+/// What the knobs tune during a `CalibrationSession`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CalibrationPage {
+    Gain,
+    Curve,
+    /// One row of the white-balance matrix: 0 for red, 1 for green, 2 for
+    /// blue.
+    WhiteBalance(usize),
+}
+
+impl CalibrationPage {
+    const ALL: [CalibrationPage; 5] = [
+        CalibrationPage::Gain,
+        CalibrationPage::Curve,
+        CalibrationPage::WhiteBalance(0),
+        CalibrationPage::WhiteBalance(1),
+        CalibrationPage::WhiteBalance(2),
+    ];
+}
+
+/// Columns of the reference ramps; the last column marks the white-balance
+/// row being tuned.
+const RAMP_STEPS: u8 = 15;
+
+/// Interactive calibration of a device with its own knobs.
+///
+/// The grid shows reference ramps, white on the top row and then red, green
+/// and blue, through the calibration being tuned.  Turning the Select knob
+/// moves between the pages listed in `CalibrationPage`, shown by the number of
+/// lit track row LEDs: one for gain, two for curves, and three for the
+/// white-balance matrix, with the row being tuned marked in the grid's last
+/// column.  Volume, Pan and Filter tune the red, green and blue values of the
+/// page and Resonance tunes all three together.  Pressing the Select knob
+/// ends the session.
+///
+/// Feed it the controller's events with `handle` and call `finish` once that
+/// says it's done.  The session draws over what the device shows without
+/// touching the shadow state, so whatever gets drawn meanwhile shows once it
+/// ends, which dropping it does too.
+pub struct CalibrationSession {
+    output: OutputHandle,
+    calibration: Calibration,
+    /// What to go back to on `cancel`.
+    original: Calibration,
+    page: usize,
+}
+
+impl CalibrationSession {
+    /// Starts tuning from the device's current calibration.
+    pub fn start(output: OutputHandle) -> Result<Self> {
+        let calibration = output.calibration();
+        let session = CalibrationSession {
+            output,
+            calibration,
+            original: calibration,
+            page: 0,
+        };
+        session.draw()?;
+        Ok(session)
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    pub fn page(&self) -> CalibrationPage {
+        CalibrationPage::ALL[self.page]
+    }
+
+    /// Handles an event, returning true once the session is done.  Knob turns
+    /// under any mode layer count.
+    pub fn handle(&mut self, event: &ControllerEvent) -> Result<bool> {
+        match *event {
+            ControllerEvent::ControlButton(ControllerButton::SelectPress, ButtonState::Down) => {
+                return Ok(true);
+            },
+            ControllerEvent::KnobTurn(ControllerKnob::Select, delta) => {
+                let pages = CalibrationPage::ALL.len() as i32;
+                self.page = (self.page as i32 + delta.signum() as i32).rem_euclid(pages) as usize;
+                self.draw()?;
+            },
+            ControllerEvent::KnobTurn(knob, delta) |
+            ControllerEvent::LayerKnobTurn(_, knob, delta) => {
+                let channels = match knob {
+                    ControllerKnob::Volume => 0..1,
+                    ControllerKnob::Pan => 1..2,
+                    ControllerKnob::Filter => 2..3,
+                    ControllerKnob::Resonance => 0..3,
+                    ControllerKnob::Select => return Ok(false),
+                };
+                for channel in channels {
+                    self.tune(channel, delta);
+                }
+                self.output.set_calibration(self.calibration)?;
+            },
+            _ => (),
+        }
+        Ok(false)
+    }
+
+    /// Ends the session keeping the tuned calibration, which is returned for
+    /// saving in a `CalibrationStore`.
+    pub fn finish(self) -> Result<Calibration> {
+        self.output.clear_overlay()?;
+        Ok(self.calibration)
+    }
+
+    /// Ends the session going back to the calibration from before.
+    pub fn cancel(self) -> Result<()> {
+        self.output.set_calibration(self.original)?;
+        self.output.clear_overlay()
+    }
+
+    fn tune(&mut self, channel: usize, delta: i8) {
+        let delta = delta as f64;
+        match self.page() {
+            CalibrationPage::Gain => {
+                let gain = &mut self.calibration.gain[channel];
+                *gain = (*gain + delta * 0.01).clamp(0.0, 2.0);
+            },
+            CalibrationPage::Curve => {
+                let curve = &mut self.calibration.curve[channel];
+                *curve = (*curve + delta * 0.02).clamp(0.2, 5.0);
+            },
+            CalibrationPage::WhiteBalance(row) => {
+                let weight = &mut self.calibration.white_balance[row][channel];
+                *weight = (*weight + delta * 0.01).clamp(-1.0, 2.0);
+            },
+        }
+    }
+
+    fn draw(&self) -> Result<()> {
+        let marked_row = match self.page() {
+            CalibrationPage::WhiteBalance(row) => Some(row as u8 + 1),
+            _ => None,
+        };
+        let mut pads = vec![];
+        for row in 0..4u8 {
+            for step in 0..RAMP_STEPS {
+                let level = ((step as u16 + 1) * 0x7f / RAMP_STEPS as u16) as u8;
+                let [r, g, b] = match row {
+                    0 => [level, level, level],
+                    1 => [level, 0, 0],
+                    2 => [0, level, 0],
+                    _ => [0, 0, level],
+                };
+                pads.push(PadColor { index: row * 16 + step, r, g, b });
+            }
+            let marker = if marked_row == Some(row) { 0x7f } else { 0 };
+            pads.push(PadColor { index: row * 16 + RAMP_STEPS, r: marker, g: marker, b: marker });
+        }
+        self.output.overlay(&ControllerCommand::PadColors(pads))?;
+        let lit = match self.page() {
+            CalibrationPage::Gain => 1,
+            CalibrationPage::Curve => 2,
+            CalibrationPage::WhiteBalance(_) => 3,
+        };
+        for row in 0..TRACK_ROWS {
+            let state = if row < lit { ButtonLedState::BrightGreen } else { ButtonLedState::Off };
+            self.output.overlay(&output::track_led_command(row, state)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CalibrationSession {
+    fn drop(&mut self) {
+        // Already done by `finish` and `cancel`, and nobody to tell otherwise.
+        let _ = self.output.clear_overlay();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::DiscoveryOptions;
+    use crate::mock::TestFire;
+
+    #[test]
+    fn identity_leaves_values_alone() {
+        let calibration = Calibration::new();
+        for rgb in [[0, 0, 0], [1, 2, 3], [0x40, 0x7f, 0x10], [0x7f; 3]].iter() {
+            assert_eq!(calibration.apply(*rgb), *rgb);
+        }
+    }
+
+    #[test]
+    fn corrections_apply_in_order() {
+        let halved = Calibration::new().with_gain([0.5, 1.0, 1.0]);
+        assert_eq!(halved.apply([0x7f, 0x7f, 0x7f]), [64, 0x7f, 0x7f]);
+        // Black stays black, and anything lit stays lit.
+        assert_eq!(halved.apply([0, 0, 0]), [0, 0, 0]);
+        assert_eq!(halved.apply([1, 0, 0]), [1, 0, 0]);
+
+        let squared = Calibration::new().with_curve([2.0, 2.0, 2.0]);
+        assert_eq!(squared.apply([64, 0x7f, 0]), [32, 0x7f, 0]);
+
+        let mut swapped = Calibration::new();
+        swapped.white_balance = [[0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+        assert_eq!(swapped.apply([0x7f, 0, 0x20]), [0, 0x7f, 0x20]);
+        // Mixing happens before the gain.
+        swapped.gain = [1.0, 0.5, 1.0];
+        assert_eq!(swapped.apply([0x7f, 0, 0]), [0, 64, 0]);
+    }
+
+    #[test]
+    fn calibration_round_trips_through_text() {
+        let mut calibration = Calibration::new()
+            .with_gain([0.9, 1.1, 0.75])
+            .with_curve([1.2, 1.0, 0.8]);
+        calibration.white_balance[1] = [0.05, 0.9, -0.1];
+        let text = calibration.to_string();
+        assert_eq!(text.parse::<Calibration>().unwrap(), calibration);
+
+        // Left out parts keep their identity values.
+        let gain_only: Calibration = "gain=0.5,1,1".parse().unwrap();
+        assert_eq!(gain_only, Calibration::new().with_gain([0.5, 1.0, 1.0]));
+        assert_eq!("".parse::<Calibration>().unwrap(), Calibration::new());
+        for bad in ["gain=1,2", "gain", "gain=a,b,c", "brightness=1,1,1"].iter() {
+            assert!(matches!(bad.parse::<Calibration>(), Err(FireError::InvalidArgument(_))),
+                    "{}", bad);
+        }
+    }
+
+    #[test]
+    fn store_round_trips_through_text() {
+        let mut store = CalibrationStore::new();
+        store.insert("fire:0a1b".parse().unwrap(), Calibration::new().with_gain([0.9, 1.0, 1.0]));
+        store.insert(DeviceId::Enumerated(2), Calibration::new().with_curve([1.5, 1.5, 1.5]));
+        let text = store.to_string();
+        assert_eq!(text.parse::<CalibrationStore>().unwrap(), store);
+
+        let edited: CalibrationStore = "# By hand\n\nfire:0A1B gain=0.9,1,1\n".parse().unwrap();
+        assert_eq!(edited.get(&DeviceId::Serial("0a1b".into())),
+                   Some(&Calibration::new().with_gain([0.9, 1.0, 1.0])));
+        assert!("fire:xyz gain=1,1,1".parse::<CalibrationStore>().is_err());
+    }
+
+    #[test]
+    fn select_cycles_through_the_pages() {
+        let fire = TestFire::attach(&DiscoveryOptions::default());
+        let mut session = CalibrationSession::start(fire.controller.output()).unwrap();
+        let turn = |knob, delta| ControllerEvent::KnobTurn(knob, delta);
+        assert_eq!(session.page(), CalibrationPage::Gain);
+        assert!(!session.handle(&turn(ControllerKnob::Select, 1)).unwrap());
+        assert_eq!(session.page(), CalibrationPage::Curve);
+        // Only the direction counts.
+        session.handle(&turn(ControllerKnob::Select, 5)).unwrap();
+        assert_eq!(session.page(), CalibrationPage::WhiteBalance(0));
+        session.handle(&turn(ControllerKnob::Select, -1)).unwrap();
+        session.handle(&turn(ControllerKnob::Select, -1)).unwrap();
+        session.handle(&turn(ControllerKnob::Select, -1)).unwrap();
+        assert_eq!(session.page(), CalibrationPage::WhiteBalance(2));
+
+        session.handle(&turn(ControllerKnob::Volume, 10)).unwrap();
+        assert!((session.calibration().white_balance[2][0] - 0.1).abs() < 1e-9);
+        let done = session.handle(&ControllerEvent::ControlButton(ControllerButton::SelectPress,
+                                                                  ButtonState::Down));
+        assert!(done.unwrap());
+        let calibration = session.finish().unwrap();
+        assert_eq!(fire.controller.output().calibration(), calibration);
+    }
+}
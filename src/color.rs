@@ -0,0 +1,190 @@
+//! Colors as picked on a screen, and how to show them on the pads.
+//!
+//! Screen colors are 8-bit sRGB, which spends its values on what the eye can
+//! tell apart rather than on light output.  The pads take 7 bits per channel
+//! that drive the LEDs more or less linearly, so colors get decoded to linear
+//! light before being scaled down.  Sending the 8-bit values halved instead
+//! makes everything but the darkest colors look washed out.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{FireError, Result};
+
+/// The sRGB decoding exponent, close enough to the real piecewise curve.
+pub const DEFAULT_GAMMA: f64 = 2.2;
+
+/// An 8-bit sRGB color.
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xff, 0xff, 0xff);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+
+    /// From hue in degrees, and saturation and value in 0.0..=1.0.
+    pub fn hsv(hue: f64, saturation: f64, value: f64) -> Self {
+        let s = clamp_unit(saturation);
+        let v = clamp_unit(value);
+        let chroma = v * s;
+        Color::from_hue(hue, chroma, v - chroma)
+    }
+
+    /// From hue in degrees, and saturation and lightness in 0.0..=1.0.
+    pub fn hsl(hue: f64, saturation: f64, lightness: f64) -> Self {
+        let s = clamp_unit(saturation);
+        let l = clamp_unit(lightness);
+        let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        Color::from_hue(hue, chroma, l - chroma / 2.0)
+    }
+
+    /// Parses "#rrggbb" or "#rgb", with or without the "#".
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        let invalid = || FireError::InvalidArgument(format!("{:?} is not a hex color", hex));
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let channel = |i: usize, len: usize| {
+            let value = u8::from_str_radix(&digits[i * len..(i + 1) * len], 16).unwrap();
+            // "#f80" means "#ff8800".
+            if len == 1 { value * 0x11 } else { value }
+        };
+        match digits.len() {
+            6 => Ok(Color::rgb(channel(0, 2), channel(1, 2), channel(2, 2))),
+            3 => Ok(Color::rgb(channel(0, 1), channel(1, 1), channel(2, 1))),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The 7-bit channel values that make a pad look like this color.
+    pub fn to_fire(self) -> [u8; 3] {
+        self.to_fire_with_gamma(DEFAULT_GAMMA)
+    }
+
+    /// Like `to_fire`, with a different decoding exponent.  Higher values
+    /// make midtones darker and more saturated.
+    pub fn to_fire_with_gamma(self, gamma: f64) -> [u8; 3] {
+        let [r, g, b] = self.to_linear(gamma);
+        [linear_to_fire(r), linear_to_fire(g), linear_to_fire(b)]
+    }
+
+    /// The color in linear light, each channel in 0.0..=1.0.
+    pub fn to_linear(self, gamma: f64) -> [f64; 3] {
+        let decode = |c: u8| (c as f64 / 255.0).powf(gamma);
+        [decode(self.r), decode(self.g), decode(self.b)]
+    }
+
+    /// Builds a color from its hue, its chroma and the amount of white `m`
+    /// added to every channel.
+    fn from_hue(hue: f64, chroma: f64, m: f64) -> Self {
+        let h = hue.rem_euclid(360.0) / 60.0;
+        let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let to_byte = |c: f64| (clamp_unit(c + m) * 255.0).round() as u8;
+        Color::rgb(to_byte(r), to_byte(g), to_byte(b))
+    }
+}
+
+/// Scales a linear light level in 0.0..=1.0 to a 7-bit pad channel.  Any
+/// light at all stays lit, since the eye is much better at telling a dim LED
+/// from an unlit one than the curve gives it credit for.
+pub fn linear_to_fire(level: f64) -> u8 {
+    let level = clamp_unit(level);
+    if level <= 0.0 {
+        0
+    } else {
+        ((level * 127.0).round() as u8).max(1)
+    }
+}
+
+fn clamp_unit(x: f64) -> f64 {
+    if x.is_nan() { 0.0 } else { x.clamp(0.0, 1.0) }
+}
+
+impl FromStr for Color {
+    type Err = FireError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Color::from_hex(s)
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_parses_both_lengths() {
+        assert_eq!(Color::from_hex("#f80").unwrap(), Color::rgb(0xff, 0x88, 0x00));
+        assert_eq!(Color::from_hex("ff8800").unwrap(), Color::rgb(0xff, 0x88, 0x00));
+        assert_eq!("#12aBcD".parse::<Color>().unwrap(), Color::rgb(0x12, 0xab, 0xcd));
+        assert_eq!(Color::rgb(0x12, 0xab, 0xcd).to_string(), "#12abcd");
+        for bad in ["", "#", "#12345", "#1234567", "#ggg", "#f8 0"].iter() {
+            assert!(matches!(Color::from_hex(bad), Err(FireError::InvalidArgument(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn hues_land_on_the_primaries() {
+        let red = Color::rgb(0xff, 0, 0);
+        let green = Color::rgb(0, 0xff, 0);
+        let blue = Color::rgb(0, 0, 0xff);
+        assert_eq!(Color::hsv(0.0, 1.0, 1.0), red);
+        assert_eq!(Color::hsv(120.0, 1.0, 1.0), green);
+        assert_eq!(Color::hsv(240.0, 1.0, 1.0), blue);
+        assert_eq!(Color::hsv(60.0, 1.0, 1.0), Color::rgb(0xff, 0xff, 0));
+        assert_eq!(Color::hsl(0.0, 1.0, 0.5), red);
+        assert_eq!(Color::hsl(120.0, 1.0, 0.5), green);
+        assert_eq!(Color::hsl(240.0, 1.0, 0.5), blue);
+        assert_eq!(Color::hsl(300.0, 0.0, 1.0), Color::WHITE);
+        assert_eq!(Color::hsv(300.0, 1.0, 0.0), Color::BLACK);
+        assert_eq!(Color::hsv(0.0, 0.0, 0.5), Color::rgb(0x80, 0x80, 0x80));
+    }
+
+    #[test]
+    fn hues_wrap_around() {
+        assert_eq!(Color::hsv(360.0, 1.0, 1.0), Color::hsv(0.0, 1.0, 1.0));
+        assert_eq!(Color::hsv(480.0, 1.0, 1.0), Color::hsv(120.0, 1.0, 1.0));
+        assert_eq!(Color::hsv(-120.0, 1.0, 1.0), Color::hsv(240.0, 1.0, 1.0));
+        assert_eq!(Color::hsl(-30.0, 1.0, 0.5), Color::hsl(330.0, 1.0, 0.5));
+    }
+
+    #[test]
+    fn gamma_darkens_midtones_but_keeps_everything_lit() {
+        assert_eq!(Color::BLACK.to_fire(), [0, 0, 0]);
+        assert_eq!(Color::WHITE.to_fire(), [0x7f, 0x7f, 0x7f]);
+        let [grey, _, _] = Color::rgb(0x80, 0x80, 0x80).to_fire();
+        assert!(grey < 40, "{}", grey);
+        let mut last = 0;
+        for c in 1..=255 {
+            let [r, _, _] = Color::rgb(c, 0, 0).to_fire();
+            assert!(r >= 1 && r >= last, "{} -> {}", c, r);
+            last = r;
+        }
+        // A lower exponent lifts the midtones.
+        let [lifted, _, _] = Color::rgb(0x80, 0, 0).to_fire_with_gamma(1.0);
+        assert_eq!(lifted, 64);
+    }
+}
@@ -0,0 +1,278 @@
+//! Everything we send to the device, mirroring `ControllerEvent` for what it
+//! sends to us.  All the understanding of the sysex messages is thanks to the
+//! Segger write-up.
+
+use crate::error::{FireError, Result};
+
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+/// Akai's manufacturer id.
+const AKAI: u8 = 0x47;
+/// "All call" device id followed by the Fire's product id.
+const FIRE_HEADER: [u8; 3] = [AKAI, 0x7f, 0x43];
+const SYSEX_PAD_COLORS: u8 = 0x65;
+const SYSEX_OLED_WRITE: u8 = 0x0e;
+/// The universal non-realtime device inquiry, addressed to all devices.
+pub(crate) const IDENTITY_REQUEST: [u8; 6] =
+    [SYSEX_START, 0x7e, 0x7f, 0x06, 0x01, SYSEX_END];
+
+/// The color of a single grid pad.  Each channel is 7 bits.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PadColor {
+    pub index: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ControllerCommand {
+    /// Sets the colors of any subset of the grid pads.
+    PadColors(Vec<PadColor>),
+    /// Sets the state of a CC-addressed LED, such as a button's.
+    ButtonLed { cc: u8, value: u8 },
+    /// Writes already packed pixel data to a rectangle of the OLED.  Bands are
+    /// 8 pixel tall rows.
+    OledWrite {
+        start_band: u8,
+        end_band: u8,
+        start_column: u8,
+        end_column: u8,
+        data: Vec<u8>,
+    },
+    /// Asks the device to identify itself with a universal sysex reply.
+    IdentityRequest,
+}
+
+fn check_7bit(what: &str, value: u8) -> Result<()> {
+    if value > 0x7f {
+        Err(FireError::InvalidArgument(format!("{} {:#x} doesn't fit in 7 bits", what, value)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes the Fire sysex header, command and 14-bit payload length.
+fn push_fire_sysex_header(buf: &mut Vec<u8>, command: u8, len: usize) -> Result<()> {
+    if len > 0x3fff {
+        return Err(FireError::InvalidArgument(
+            format!("sysex payload of {} bytes is too long", len)));
+    }
+    buf.push(SYSEX_START);
+    buf.extend_from_slice(&FIRE_HEADER);
+    buf.push(command);
+    buf.push(((len >> 7) & 0x7f) as u8);
+    buf.push((len & 0x7f) as u8);
+    Ok(())
+}
+
+fn malformed(what: &str) -> FireError {
+    FireError::InvalidArgument(format!("malformed message: {}", what))
+}
+
+impl ControllerCommand {
+    /// Encodes the command into `buf`, replacing its contents, so that a
+    /// buffer can be reused across messages.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        match self {
+            ControllerCommand::PadColors(pads) => {
+                push_fire_sysex_header(buf, SYSEX_PAD_COLORS, pads.len() * 4)?;
+                for pad in pads {
+                    check_7bit("pad index", pad.index)?;
+                    check_7bit("red", pad.r)?;
+                    check_7bit("green", pad.g)?;
+                    check_7bit("blue", pad.b)?;
+                    buf.extend_from_slice(&[pad.index, pad.r, pad.g, pad.b]);
+                }
+                buf.push(SYSEX_END);
+            },
+            ControllerCommand::ButtonLed { cc, value } => {
+                check_7bit("cc", *cc)?;
+                check_7bit("value", *value)?;
+                buf.extend_from_slice(&[0xb0, *cc, *value]);
+            },
+            ControllerCommand::OledWrite { start_band, end_band, start_column, end_column, data } => {
+                check_7bit("start band", *start_band)?;
+                check_7bit("end band", *end_band)?;
+                check_7bit("start column", *start_column)?;
+                check_7bit("end column", *end_column)?;
+                push_fire_sysex_header(buf, SYSEX_OLED_WRITE, 4 + data.len())?;
+                buf.extend_from_slice(&[*start_band, *end_band, *start_column, *end_column]);
+                for &b in data {
+                    check_7bit("pixel data", b)?;
+                    buf.push(b);
+                }
+                buf.push(SYSEX_END);
+            },
+            ControllerCommand::IdentityRequest => {
+                buf.extend_from_slice(&IDENTITY_REQUEST);
+            },
+        }
+        Ok(())
+    }
+
+    /// Decodes a single complete message as produced by `encode`.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes {
+            [0xb0, cc, value] if *cc <= 0x7f && *value <= 0x7f => {
+                Ok(ControllerCommand::ButtonLed { cc: *cc, value: *value })
+            },
+            _ if bytes == IDENTITY_REQUEST => Ok(ControllerCommand::IdentityRequest),
+            [SYSEX_START, a, b, c, command, len_hi, len_lo, payload @ .., SYSEX_END]
+                if [*a, *b, *c] == FIRE_HEADER => {
+                let len = ((*len_hi as usize & 0x7f) << 7) | (*len_lo as usize & 0x7f);
+                if payload.len() != len {
+                    return Err(malformed("sysex length doesn't match payload"));
+                }
+                if payload.iter().any(|&b| b > 0x7f) {
+                    return Err(malformed("sysex payload isn't 7-bit"));
+                }
+                match *command {
+                    SYSEX_PAD_COLORS => {
+                        if !len.is_multiple_of(4) {
+                            return Err(malformed("pad colors aren't 4-byte tuples"));
+                        }
+                        Ok(ControllerCommand::PadColors(payload.chunks(4).map(|c| {
+                            PadColor { index: c[0], r: c[1], g: c[2], b: c[3] }
+                        }).collect()))
+                    },
+                    SYSEX_OLED_WRITE => match payload {
+                        [start_band, end_band, start_column, end_column, data @ ..] => {
+                            Ok(ControllerCommand::OledWrite {
+                                start_band: *start_band,
+                                end_band: *end_band,
+                                start_column: *start_column,
+                                end_column: *end_column,
+                                data: data.to_vec(),
+                            })
+                        },
+                        _ => Err(malformed("OLED write is missing its rectangle")),
+                    },
+                    _ => Err(malformed("unknown Fire sysex command")),
+                }
+            },
+            _ => Err(malformed("not a Fire command")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Xorshift, so the cases are random-ish but the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u8 {
+            (self.next() % n) as u8
+        }
+    }
+
+    fn round_trip(command: &ControllerCommand) {
+        let mut buf = vec![];
+        command.encode(&mut buf).unwrap();
+        assert_eq!(&ControllerCommand::decode(&buf).unwrap(), command);
+    }
+
+    #[test]
+    fn button_leds_round_trip() {
+        for cc in 0..=0x7f {
+            for value in 0..=0x7f {
+                round_trip(&ControllerCommand::ButtonLed { cc, value });
+            }
+        }
+    }
+
+    #[test]
+    fn identity_request_round_trips() {
+        round_trip(&ControllerCommand::IdentityRequest);
+    }
+
+    #[test]
+    fn pad_colors_round_trip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let count = rng.below(65) as usize;
+            let pads = (0..count).map(|_| PadColor {
+                index: rng.below(64),
+                r: rng.below(0x80),
+                g: rng.below(0x80),
+                b: rng.below(0x80),
+            }).collect();
+            round_trip(&ControllerCommand::PadColors(pads));
+        }
+    }
+
+    #[test]
+    fn oled_writes_round_trip() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..200 {
+            let start_band = rng.below(8);
+            let end_band = start_band + rng.below(8 - start_band as u64);
+            let start_column = rng.below(128);
+            let end_column = start_column + rng.below(128 - start_column as u64);
+            let len = rng.below(256) as usize * 4;
+            let data = (0..len).map(|_| rng.below(0x80)).collect();
+            round_trip(&ControllerCommand::OledWrite {
+                start_band, end_band, start_column, end_column, data,
+            });
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_dont_encode() {
+        let mut buf = vec![];
+        assert!(ControllerCommand::ButtonLed { cc: 0x80, value: 0 }.encode(&mut buf).is_err());
+        assert!(ControllerCommand::ButtonLed { cc: 0, value: 0x80 }.encode(&mut buf).is_err());
+        let pad = PadColor { index: 0, r: 0x80, g: 0, b: 0 };
+        assert!(ControllerCommand::PadColors(vec![pad]).encode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let mut buf = vec![];
+        ControllerCommand::PadColors(vec![PadColor { index: 1, r: 2, g: 3, b: 4 }])
+            .encode(&mut buf).unwrap();
+
+        // The length says 4 bytes, the payload has 3.
+        let mut short = buf.clone();
+        short.remove(buf.len() - 2);
+        assert!(ControllerCommand::decode(&short).is_err());
+
+        // A length that matches but isn't a whole number of pads.
+        let mut ragged = short.clone();
+        ragged[6] = 3;
+        assert!(ControllerCommand::decode(&ragged).is_err());
+
+        let mut wide = buf.clone();
+        wide[8] = 0x80;
+        assert!(ControllerCommand::decode(&wide).is_err());
+
+        let mut unknown = buf.clone();
+        unknown[4] = 0x12;
+        assert!(ControllerCommand::decode(&unknown).is_err());
+
+        let mut not_fire = buf.clone();
+        not_fire[1] = 0x41;
+        assert!(ControllerCommand::decode(&not_fire).is_err());
+
+        assert!(ControllerCommand::decode(&[0xb0, 0x80, 0x00]).is_err());
+        assert!(ControllerCommand::decode(&[0xb0, 0x10]).is_err());
+        assert!(ControllerCommand::decode(&[0x90, 0x36, 0x7f]).is_err());
+        assert!(ControllerCommand::decode(&[]).is_err());
+
+        // An OLED write too short to hold its rectangle.
+        let oled = [SYSEX_START, AKAI, 0x7f, 0x43, SYSEX_OLED_WRITE, 0x00, 0x02, 0x00, 0x07,
+                    SYSEX_END];
+        assert!(ControllerCommand::decode(&oled).is_err());
+    }
+}
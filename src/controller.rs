@@ -0,0 +1,739 @@
+use std::cmp::{Eq, PartialEq};
+use std::hash::{Hash, Hasher};
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::brightness::LedRegion;
+use crate::calibration::Calibration;
+use crate::color::Color;
+use crate::command::ControllerCommand;
+use crate::device::{DeviceId, DeviceIdentity};
+use crate::discovery::{find_fire_ports, DiscoveryOptions, FirePorts};
+use crate::error::{FireError, Result};
+use crate::input::{
+    ButtonState, ChannelMode, ControllerButton, ControllerEvent, ControllerKnob, EventEnvelope,
+};
+use crate::oled::OledBitmap;
+use crate::output::{self, ButtonLedState, PadLedBuffer};
+use crate::parser::{MidiParser, ParsedMessage};
+use crate::queue::{self, EventReceiver, EventSender};
+use crate::shadow::DeviceShadow;
+use crate::transport::{InputCallback, InputConnection, MidiTransport};
+use crate::writer::OutputHandle;
+
+struct ConnectedController {
+    ports: FirePorts,
+    // Only held to keep the input callback alive.
+    #[allow(dead_code)]
+    in_conn: Box<dyn InputConnection>,
+}
+
+enum ControllerState {
+    Disconnected,
+    Connected(ConnectedController),
+}
+
+/// How long to wait for a device to answer the identity request.
+const IDENTITY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How a controller being connected learns who its device is.
+enum Identify {
+    /// Ask the device and wait for its answer.
+    Ask,
+    /// Whoever connects it already asked, see `probe_identity`.
+    Probed(Option<DeviceIdentity>),
+}
+
+pub struct FireController {
+    /// Identifier for the controller, derived from `identity` when possible.
+    /// This never changes once attached, even across reconnects.
+    id: DeviceId,
+    /// Copy of `id` for the input callbacks and the writer.
+    shared_id: Arc<Mutex<DeviceId>>,
+    /// Events from the device held back while connecting, so that they go out
+    /// after `ControllerEvent::Connected` and under the final id.  None once
+    /// connected.
+    held: Arc<Mutex<Option<Vec<EventEnvelope>>>>,
+    /// The current mode if mode cycling is on.  Shared with the input
+    /// callbacks, which do the cycling.
+    mode: Arc<Mutex<Option<ChannelMode>>>,
+    /// What the device told us about itself, if it answered our identity
+    /// request.
+    identity: Option<DeviceIdentity>,
+    state: ControllerState,
+    /// Each connection's input callback gets a clone of this, so the event
+    /// stream survives reconnects.
+    tx: EventSender,
+    event_rx: Option<EventReceiver>,
+
+    leds: PadLedBuffer,
+    oled: OledBitmap,
+    /// Queues commands for the writer thread, which owns the output
+    /// connection and the shadow state.
+    output: OutputHandle,
+    /// What to leave the device showing on shutdown.
+    goodbye: DeviceShadow,
+    /// Whether dropping the controller runs `shutdown`.
+    shutdown_on_drop: bool,
+}
+
+
+impl FireController {
+    /// Finds all Fire controllers on the system and returns them in a vector.
+    ///
+    /// The outer `Result` fails if the MIDI ports couldn't be enumerated at
+    /// all.  Each found device then gets its own `Result` so that one busy or
+    /// vanished device doesn't prevent the use of the others.
+    pub fn attach_to_all() -> Result<Vec<Result<FireController>>> {
+        FireController::discover(&DiscoveryOptions::default())
+    }
+
+    /// Like `attach_to_all`, but with the given options, using the midir
+    /// transport.
+    pub fn discover(options: &DiscoveryOptions) -> Result<Vec<Result<FireController>>> {
+        FireController::attach_to_all_with(&options.midir_transport(), options)
+    }
+
+    /// Like `attach_to_all`, but finds and connects to the controllers using
+    /// the given transport and options.
+    pub fn attach_to_all_with(transport: &dyn MidiTransport, options: &DiscoveryOptions)
+        -> Result<Vec<Result<FireController>>> {
+        let ports = find_fire_ports(transport, options)?;
+        let mut controllers = vec![];
+        for (i, p) in ports.iter().enumerate() {
+            // Serial filters need to know who's on the port before deciding,
+            // and then attaching doesn't ask again.
+            let probed = if options.needs_identity() {
+                match probe_identity(transport, p) {
+                    Ok(identity) => Some(identity),
+                    Err(e) => {
+                        controllers.push(Err(e));
+                        continue;
+                    },
+                }
+            } else {
+                None
+            };
+            if !options.accepts(&p.name, probed.as_ref().and_then(Option::as_ref)) {
+                continue;
+            }
+            controllers.push(match probed {
+                Some(identity) => {
+                    FireController::attach_probed(transport, options, i as u32, p, identity)
+                },
+                None => FireController::attach(transport, options, i as u32, p),
+            });
+        }
+        Ok(controllers)
+    }
+
+    /// Connects to the given pair of ports and asks the device to identify
+    /// itself.  If the device doesn't answer with a serial number, it gets
+    /// `DeviceId::Enumerated(index)` as its id.
+    ///
+    /// The options' include and exclude filters aren't consulted; those are
+    /// for deciding what to attach in the first place.
+    pub fn attach(transport: &dyn MidiTransport, options: &DiscoveryOptions, index: u32,
+                  ports: &FirePorts) -> Result<FireController> {
+        FireController::attach_as(transport, options, index, ports, Identify::Ask)
+    }
+
+    /// Like `attach`, but for a device `probe_identity` just asked, so that
+    /// attaching doesn't wait on it again.
+    pub(crate) fn attach_probed(transport: &dyn MidiTransport, options: &DiscoveryOptions,
+                                index: u32, ports: &FirePorts,
+                                identity: Option<DeviceIdentity>) -> Result<FireController> {
+        FireController::attach_as(transport, options, index, ports, Identify::Probed(identity))
+    }
+
+    fn attach_as(transport: &dyn MidiTransport, options: &DiscoveryOptions, index: u32,
+                 ports: &FirePorts, identify: Identify) -> Result<FireController> {
+        let (tx, rx) = queue::channel(options.channel_capacity(), options.overflow_policy());
+        let shared_id = Arc::new(Mutex::new(DeviceId::Enumerated(index)));
+        let on_lost = {
+            let tx = tx.clone();
+            let shared_id = shared_id.clone();
+            Box::new(move || {
+                let device = shared_id.lock().unwrap().clone();
+                tx.force_send(own_event(device, ControllerEvent::Disconnected));
+            })
+        };
+        let mut controller = FireController {
+            id: DeviceId::Enumerated(index),
+            shared_id,
+            held: Arc::new(Mutex::new(None)),
+            mode: Arc::new(Mutex::new(None)),
+            identity: None,
+            state: ControllerState::Disconnected,
+            tx,
+            event_rx: Some(rx),
+            leds: PadLedBuffer::new(),
+            oled: OledBitmap::new(),
+            output: OutputHandle::spawn(on_lost),
+            goodbye: DeviceShadow::new(),
+            shutdown_on_drop: true,
+        };
+
+        controller.open(transport, ports, identify)?;
+        if let Some(id) = controller.identity.as_ref().and_then(|i| i.device_id()) {
+            *controller.shared_id.lock().unwrap() = id.clone();
+            controller.id = id;
+        }
+        if let Some(calibration) = options.calibrations().get(&controller.id) {
+            controller.output.set_calibration(*calibration)?;
+        }
+        if options.clear_on_attach() {
+            // The shadow starts out blank.
+            controller.resync()?;
+        }
+        controller.emit(ControllerEvent::Connected);
+        controller.release_held();
+        Ok(controller)
+    }
+
+    /// Connects to the given pair of ports, replacing any current connection,
+    /// replays the shadow state and emits `ControllerEvent::Connected` once
+    /// done.  This is how a controller gets reattached to its device after it
+    /// was unplugged.
+    pub fn connect(&mut self, transport: &dyn MidiTransport, ports: &FirePorts)
+        -> Result<()> {
+        self.connect_as(transport, ports, Identify::Ask)
+    }
+
+    /// Like `connect`, but for a device `probe_identity` just asked.
+    pub(crate) fn connect_probed(&mut self, transport: &dyn MidiTransport, ports: &FirePorts,
+                                 identity: Option<DeviceIdentity>) -> Result<()> {
+        self.connect_as(transport, ports, Identify::Probed(identity))
+    }
+
+    fn connect_as(&mut self, transport: &dyn MidiTransport, ports: &FirePorts,
+                  identify: Identify) -> Result<()> {
+        self.disconnect();
+        let result = self.open(transport, ports, identify).and_then(|_| self.resync());
+        if result.is_ok() {
+            self.emit(ControllerEvent::Connected);
+        }
+        self.release_held();
+        result
+    }
+
+    /// Opens the connections and, unless `identify` says the device was
+    /// already asked, does the identity handshake.
+    fn open(&mut self, transport: &dyn MidiTransport, ports: &FirePorts, identify: Identify)
+        -> Result<()> {
+        let (identity_tx, identity_rx) = std_mpsc::channel::<DeviceIdentity>();
+        *self.held.lock().unwrap() = Some(vec![]);
+        let callback = self.input_callback(identity_tx);
+        self.tx.open_input();
+        let in_conn = transport.connect_input(ports.input, callback)?;
+        let connected = transport.connect_output(ports.output)
+            .and_then(|out_conn| self.output.connect(out_conn));
+        if let Err(e) = connected {
+            // Before `in_conn` goes.
+            self.tx.close_input();
+            return Err(e);
+        }
+        self.state = ControllerState::Connected(ConnectedController {
+            ports: ports.clone(),
+            in_conn,
+        });
+
+        match identify {
+            Identify::Ask => {
+                self.send_command(&ControllerCommand::IdentityRequest)?;
+                if let Ok(identity) = identity_rx.recv_timeout(IDENTITY_TIMEOUT) {
+                    self.identity = Some(identity);
+                }
+            },
+            Identify::Probed(Some(identity)) => self.identity = Some(identity),
+            Identify::Probed(None) => (),
+        }
+        Ok(())
+    }
+
+    /// Sends on the events held back while connecting, stamped with the
+    /// controller's id.
+    fn release_held(&mut self) {
+        // Holding the lock keeps newer events from the device from getting
+        // ahead.
+        let mut held = self.held.lock().unwrap();
+        for mut envelope in held.take().unwrap_or_default() {
+            envelope.device = self.id.clone();
+            // They already waited; the channel's policy can't apply to them
+            // without blocking the thread that attaches.
+            self.tx.force_send(envelope);
+        }
+    }
+
+    /// Drops the connection to the device, if any, emitting
+    /// `ControllerEvent::Disconnected`.
+    pub fn disconnect(&mut self) {
+        if let ControllerState::Connected(_) = self.state {
+            // The input callback may be blocked on a full channel, and
+            // dropping the connection waits for it.
+            self.tx.close_input();
+            self.state = ControllerState::Disconnected;
+            // Unless the writer already lost the connection and said so.
+            if self.output.disconnect() {
+                self.emit(ControllerEvent::Disconnected);
+            }
+        }
+    }
+
+    /// Whether the controller is connected to its device.  This turns false
+    /// as soon as sending to the device fails.
+    pub fn is_connected(&self) -> bool {
+        match self.state {
+            ControllerState::Connected(_) => self.output.is_connected(),
+            ControllerState::Disconnected => false,
+        }
+    }
+
+    /// The ports the controller is currently connected to.
+    pub fn ports(&self) -> Option<&FirePorts> {
+        match &self.state {
+            ControllerState::Connected(cs) if self.output.is_connected() => Some(&cs.ports),
+            _ => None,
+        }
+    }
+
+    /// Builds the callback for a new input connection.  Identity replies go to
+    /// `identity_tx`, everything else into the event channel.
+    fn input_callback(&self, identity_tx: std_mpsc::Sender<DeviceIdentity>) -> InputCallback {
+        let tx = self.tx.clone();
+        let shared_id = self.shared_id.clone();
+        let held = self.held.clone();
+        let mode = self.mode.clone();
+        let output = self.output.clone();
+        let mut parser = MidiParser::new();
+        Box::new(move |stamp, msg| {
+            let received = Instant::now();
+            parser.feed(msg, |parsed| {
+                let event = match parsed {
+                    ParsedMessage::Event(event) => event,
+                    ParsedMessage::Unparsed(bytes) => ControllerEvent::Unparsed(bytes),
+                    ParsedMessage::Sysex(bytes) => {
+                        match DeviceIdentity::parse(&bytes) {
+                            Some(identity) => {
+                                // Nobody is listening once the handshake is
+                                // done.
+                                let _ = identity_tx.send(identity);
+                                return;
+                            },
+                            None => ControllerEvent::Unparsed(bytes),
+                        }
+                    },
+                    // The Fire doesn't send anything we care about this way.
+                    ParsedMessage::Realtime(_) => return,
+                };
+                let (event, switched) = cycle_mode(&mode, event);
+                let device = shared_id.lock().unwrap().clone();
+                let envelope = EventEnvelope { device: device.clone(), stamp, received, event };
+                let mode_changed = switched.map(|next| {
+                    // A lost connection gets reported elsewhere.
+                    let _ = output.set_mode_led(Some(next));
+                    let event = ControllerEvent::ModeChanged(next);
+                    EventEnvelope { device, stamp, received, event }
+                });
+                if let Some(held) = held.lock().unwrap().as_mut() {
+                    held.push(envelope);
+                    held.extend(mode_changed);
+                    return;
+                }
+                // We can't propagate anything out of the MIDI thread, so
+                // overflows are left for `check_input` to report.
+                tx.send(envelope);
+                if let Some(envelope) = mode_changed {
+                    tx.force_send(envelope);
+                }
+            });
+        })
+    }
+
+    /// Queues an event that didn't come from the device itself.  These don't
+    /// count against the channel's capacity.
+    fn emit(&mut self, event: ControllerEvent) {
+        self.tx.force_send(own_event(self.id.clone(), event));
+    }
+
+    pub fn id(&self) -> &DeviceId {
+        &self.id
+    }
+
+    /// What the device reported about itself, if it answered our identity
+    /// request.
+    pub fn identity(&self) -> Option<&DeviceIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Takes the stream of events from the controller.  This can only be done
+    /// once; subsequent calls return None.
+    pub fn take_event_rx(&mut self) -> Option<EventReceiver> {
+        self.event_rx.take()
+    }
+
+    /// Reports `FireError::ChannelOverflow` if any events were dropped since
+    /// the last call because the event channel was full.
+    pub fn check_input(&self) -> Result<()> {
+        if self.tx.take_overflowed() {
+            Err(FireError::ChannelOverflow)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of events from the device dropped so far because the event
+    /// channel was full, see `OverflowPolicy`.
+    pub fn dropped_events(&self) -> u64 {
+        self.tx.dropped()
+    }
+
+    /// Do a basic 4x4 color cube cut into 4 slices.
+    pub fn set_color_cube(&mut self) {
+        self.leds.set_color_cube();
+    }
+
+    pub fn set_led(&mut self, i: u8, r: u8, g: u8, b: u8) -> Result<()> {
+        self.leds.set_led(i, r, g, b)
+    }
+
+    /// Sets pad `i` to look like an 8-bit screen color, see `Color::to_fire`.
+    pub fn set_color(&mut self, i: u8, color: Color) -> Result<()> {
+        self.leds.set_color(i, color)
+    }
+
+    /// Sets the corrections applied to the pad colors on their way to the
+    /// device, see `Calibration`.
+    pub fn set_calibration(&mut self, calibration: Calibration) -> Result<()> {
+        self.output.set_calibration(calibration)
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.output.calibration()
+    }
+
+    /// Fades every pad and button LED to `level` over `fade`, see
+    /// `OutputHandle::set_brightness`.
+    pub fn set_brightness(&mut self, level: f64, fade: Duration) -> Result<()> {
+        self.output.set_brightness(level, fade)
+    }
+
+    pub fn brightness(&self) -> f64 {
+        self.output.brightness()
+    }
+
+    /// Fades the brightness multiplier for part of the device, see
+    /// `OutputHandle::set_region_brightness`.
+    pub fn set_region_brightness(&mut self, region: LedRegion, level: f64, fade: Duration)
+                                 -> Result<()> {
+        self.output.set_region_brightness(region, level, fade)
+    }
+
+    pub fn update_leds(&mut self) -> Result<()> {
+        let command = self.leds.to_command();
+        self.send_command(&command)
+    }
+
+    /// Sets a button's LED, see `ControllerButton::led_states` for what each
+    /// button can show.  The shadow state records what it's showing.
+    pub fn set_button_led(&mut self, button: ControllerButton, state: ButtonLedState)
+        -> Result<()> {
+        self.output.set_button_led(button, state)
+    }
+
+    /// Sets the LED next to track row `row` (0-based, top to bottom), see
+    /// `output::track_led_states` for what it can show.
+    pub fn set_track_led(&mut self, row: u8, state: ButtonLedState) -> Result<()> {
+        self.output.set_track_led(row, state)
+    }
+
+    /// Lights the indicator LED for `mode`, or none of them.  With mode
+    /// cycling on, the next press of Channel moves on from the current mode
+    /// regardless.
+    pub fn set_mode_led(&mut self, mode: Option<ChannelMode>) -> Result<()> {
+        self.output.set_mode_led(mode)
+    }
+
+    /// Turns the built-in handling of the Channel button on or off.  While
+    /// on, every press of Channel switches to the next mode, lights its
+    /// indicator and emits `ControllerEvent::ModeChanged`, and turns of the
+    /// Volume, Pan, Filter and Resonance knobs are reported as
+    /// `ControllerEvent::LayerKnobTurn` under the current mode.  Cycling
+    /// starts out in `ChannelMode::Channel`; turning it off turns the
+    /// indicators off.
+    pub fn set_mode_cycling(&mut self, on: bool) -> Result<()> {
+        let mode = {
+            let mut mode = self.mode.lock().unwrap();
+            *mode = if on { Some(mode.unwrap_or(ChannelMode::Channel)) } else { None };
+            *mode
+        };
+        self.output.set_mode_led(mode)
+    }
+
+    /// The current mode if mode cycling is on.
+    pub fn mode(&self) -> Option<ChannelMode> {
+        *self.mode.lock().unwrap()
+    }
+
+    /// The bitmap drawn to the OLED by `update_oled`.
+    pub fn oled_mut(&mut self) -> &mut OledBitmap {
+        &mut self.oled
+    }
+
+    pub fn update_oled(&mut self) -> Result<()> {
+        let command = self.oled.to_command();
+        self.send_command(&command)
+    }
+
+    /// A handle for drawing to the device from other tasks and threads.
+    pub fn output(&self) -> OutputHandle {
+        self.output.clone()
+    }
+
+    /// Batches drawing into frames sent at most once per `interval`, see
+    /// `OutputHandle::set_frame_interval`.
+    pub fn set_frame_interval(&self, interval: Option<Duration>) -> Result<()> {
+        self.output.set_frame_interval(interval)
+    }
+
+    /// A copy of what the device should be showing given everything sent to
+    /// it.
+    pub fn shadow(&self) -> DeviceShadow {
+        self.output.shadow()
+    }
+
+    /// Sends the shadow state to the device again, for when the device may
+    /// have lost it, such as after a power glitch.  This happens automatically
+    /// when a controller gets reconnected.
+    pub fn resync(&mut self) -> Result<()> {
+        self.output.resync()
+    }
+
+    /// Sets what `shutdown` leaves the device showing.  By default that's
+    /// nothing at all.
+    pub fn set_goodbye_frame(&mut self, frame: DeviceShadow) {
+        self.goodbye = frame;
+    }
+
+    /// Sets whether dropping the controller runs `shutdown`, which it does by
+    /// default so that an exiting process doesn't leave the device lit up as
+    /// if it were still in use.
+    pub fn set_shutdown_on_drop(&mut self, shutdown: bool) {
+        self.shutdown_on_drop = shutdown;
+    }
+
+    /// Draws the goodbye frame, blank by default, turning off every button,
+    /// track row and mode LED it doesn't mention, and then disconnects.  Does
+    /// nothing if the controller isn't connected.
+    pub fn shutdown(&mut self) -> Result<()> {
+        if !self.is_connected() {
+            return Ok(());
+        }
+        let mut commands = self.goodbye.replay();
+        // Whatever the shadow says, since LEDs lit before attaching aren't in
+        // it.
+        for cc in output::led_ccs() {
+            if self.goodbye.cc_led(cc).is_none() {
+                commands.push(ControllerCommand::ButtonLed { cc, value: output::cc_led_off(cc) });
+            }
+        }
+        let result = commands.iter().try_for_each(|command| self.send_command(command));
+        // A calibration session still going would keep its drawing on top.
+        // Dropped after the goodbye frame is in the shadow, so that it's what
+        // shows up underneath.
+        let result = result.and_then(|_| self.output.clear_overlay());
+        // Make sure it all went out before the process gets a chance to exit.
+        let result = result.and_then(|_| self.output.flush());
+        self.disconnect();
+        result
+    }
+
+    /// Queues a single command for the device, see
+    /// `OutputHandle::send_command`.
+    pub fn send_command(&self, command: &ControllerCommand) -> Result<()> {
+        self.output.send_command(command)
+    }
+}
+
+/// With mode cycling on, has a press of the Channel button switch to the next
+/// mode, which is returned along with the event, and puts knob turns in the
+/// current mode's layer.
+fn cycle_mode(mode: &Mutex<Option<ChannelMode>>, event: ControllerEvent)
+    -> (ControllerEvent, Option<ChannelMode>) {
+    let mut mode = mode.lock().unwrap();
+    let current = match *mode {
+        Some(current) => current,
+        None => return (event, None),
+    };
+    match event {
+        ControllerEvent::ControlButton(ControllerButton::Channel, ButtonState::Down) => {
+            let next = current.next();
+            *mode = Some(next);
+            (event, Some(next))
+        },
+        ControllerEvent::KnobTurn(knob, delta) if knob != ControllerKnob::Select => {
+            (ControllerEvent::LayerKnobTurn(current, knob, delta), None)
+        },
+        _ => (event, None),
+    }
+}
+
+/// Wraps an event that didn't come from the device itself.
+fn own_event(device: DeviceId, event: ControllerEvent) -> EventEnvelope {
+    EventEnvelope { device, stamp: 0, received: Instant::now(), event }
+}
+
+/// Briefly connects to the given ports to ask the device who it is, so that a
+/// returning device can be matched up with its old controller before
+/// anything gets attached.
+pub fn probe_identity(transport: &dyn MidiTransport, ports: &FirePorts)
+    -> Result<Option<DeviceIdentity>> {
+    let (identity_tx, identity_rx) = std_mpsc::channel::<DeviceIdentity>();
+    let mut parser = MidiParser::new();
+    let _in_conn = transport.connect_input(ports.input, Box::new(move |_stamp, msg| {
+        parser.feed(msg, |parsed| {
+            if let ParsedMessage::Sysex(bytes) = parsed {
+                if let Some(identity) = DeviceIdentity::parse(&bytes) {
+                    let _ = identity_tx.send(identity);
+                }
+            }
+        });
+    }))?;
+    let mut out_conn = transport.connect_output(ports.output)?;
+    let mut buf = Vec::new();
+    ControllerCommand::IdentityRequest.encode(&mut buf)?;
+    out_conn.send(&buf)?;
+    Ok(identity_rx.recv_timeout(IDENTITY_TIMEOUT).ok())
+}
+
+impl Drop for FireController {
+    fn drop(&mut self) {
+        if self.shutdown_on_drop {
+            // There's nobody to tell if this fails.
+            let _ = self.shutdown();
+        }
+        // Handles may keep the writer around, but not the connection.
+        self.output.disconnect();
+        // The input connection goes with the fields, see `disconnect`.
+        self.tx.close_input();
+    }
+}
+
+impl Hash for FireController {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl Eq for FireController {}
+
+impl PartialEq for FireController {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::CalibrationSession;
+    use crate::command::IDENTITY_REQUEST;
+    use crate::discovery::DeviceFilter;
+    use crate::mock::{MockTransport, TestFire};
+
+    #[test]
+    fn events_during_the_handshake_follow_connected_under_the_final_id() {
+        let transport = MockTransport::new();
+        let (input, output) = transport.add_device("FL STUDIO FIRE");
+        // A pad press sneaks in ahead of the identity reply.
+        let reply = [0x90, 0x36, 0x7f,
+                     0xf0, 0x7e, 0x00, 0x06, 0x02, 0x47, 0x43, 0x00, 0x19, 0x00,
+                     0x01, 0x00, 0x00, 0x00, 0x12, 0x34, 0xf7];
+        transport.respond_to(output, &IDENTITY_REQUEST, input, &reply);
+        let mut controller = FireController::attach_to_all_with(
+            &transport, &DiscoveryOptions::default()).unwrap().remove(0).unwrap();
+        let mut rx = controller.take_event_rx().unwrap();
+
+        let id = DeviceId::Serial("1234".into());
+        assert_eq!(controller.id(), &id);
+        let first = rx.try_recv().unwrap();
+        assert_eq!((first.device, first.event), (id.clone(), ControllerEvent::Connected));
+        let second = rx.try_recv().unwrap();
+        assert_eq!(second.device, id);
+        assert!(matches!(second.event, ControllerEvent::GridButton(0, _, _, ButtonState::Down, _)));
+
+        // Once connected, events go straight through.  The reply is fed from
+        // the writer thread, which the mock doesn't let overlap with ours.
+        controller.output().flush().unwrap();
+        assert!(transport.feed_input(input, 1, &[0x80, 0x36, 0x00]));
+        assert!(matches!(rx.try_recv().unwrap().event,
+                         ControllerEvent::GridButton(0, _, _, ButtonState::Up, _)));
+    }
+
+    #[test]
+    fn shutdown_turns_the_mode_indicators_off() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.controller.set_mode_led(Some(ChannelMode::Mixer)).unwrap();
+        fire.controller.set_button_led(ControllerButton::Play, ButtonLedState::BrightGreen)
+            .unwrap();
+        fire.sent();
+
+        fire.controller.shutdown().unwrap();
+        let sent = fire.transport.take_sent(fire.output);
+        assert!(sent.contains(&vec![0xb0, 0x1b, 0x10]));
+        assert!(sent.contains(&vec![0xb0, ControllerButton::Play.note(), 0x00]));
+        assert!(!sent.contains(&vec![0xb0, 0x1b, 0x00]));
+    }
+
+    #[test]
+    fn serial_filters_ask_for_the_identity_once() {
+        let transport = MockTransport::new();
+        let (_, output) = transport.add_fire("FL STUDIO FIRE", &[0x0a]);
+        let options = DiscoveryOptions::default().exclude(DeviceFilter::Serial("0b".into()));
+        let controller = FireController::attach_to_all_with(&transport, &options).unwrap()
+            .remove(0).unwrap();
+        controller.output().flush().unwrap();
+        assert_eq!(controller.id(), &DeviceId::Serial("0a".into()));
+        let requests = transport.take_sent(output).into_iter()
+            .filter(|message| *message == IDENTITY_REQUEST).count();
+        assert_eq!(requests, 1);
+    }
+
+    #[test]
+    fn shutdown_turns_off_leds_lit_before_attaching() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.controller.shutdown().unwrap();
+        let sent = fire.transport.take_sent(fire.output);
+        for button in ControllerButton::all().filter(|b| !b.led_states().is_empty()) {
+            assert!(sent.contains(&vec![0xb0, button.note(), 0x00]), "{:?}", button);
+        }
+        for row in 0..4 {
+            assert!(sent.contains(&vec![0xb0, 0x28 + row, 0x00]));
+        }
+        assert!(sent.contains(&vec![0xb0, 0x1b, 0x10]));
+        // Channel has no LED of its own.
+        assert!(!sent.contains(&vec![0xb0, ControllerButton::Channel.note(), 0x00]));
+    }
+
+    #[test]
+    fn shutdown_ends_a_calibration_session() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        let _session = CalibrationSession::start(fire.controller.output()).unwrap();
+        fire.sent();
+
+        fire.controller.shutdown().unwrap();
+        let sent = fire.transport.take_sent(fire.output);
+        let mut pads = [None; 64];
+        for message in &sent {
+            if let Ok(ControllerCommand::PadColors(colors)) = ControllerCommand::decode(message) {
+                for pad in colors {
+                    pads[pad.index as usize] = Some([pad.r, pad.g, pad.b]);
+                }
+            }
+        }
+        assert!(pads.iter().all(|pad| *pad == Some([0, 0, 0])), "{:?}", &pads[..]);
+        // The gain page lights the first track row LED; the others are off
+        // already.
+        assert!(sent.contains(&vec![0xb0, 0x28, 0x00]));
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::{FireError, Result};
+
+/// Identifies a controller.
+///
+/// Devices that report a serial number in their identity reply keep the same
+/// id across re-plugging and restarts.  The rest fall back to their position
+/// in enumeration order, which can change whenever devices come and go.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceId {
+    /// The serial number from the device's identity reply, in hex.
+    Serial(Arc<str>),
+    /// The device's position in enumeration order.
+    Enumerated(u32),
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceId::Serial(serial) => write!(f, "fire:{}", serial),
+            DeviceId::Enumerated(index) => write!(f, "fire#{}", index),
+        }
+    }
+}
+
+/// Reads back what `Display` writes.  Serials may be in either case but come
+/// out in lowercase, like the ones `DeviceIdentity::device_id` makes, so that
+/// hand-edited ids still match their devices.
+impl FromStr for DeviceId {
+    type Err = FireError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || FireError::InvalidArgument(format!("{:?} is not a device id", s));
+        if let Some(serial) = s.strip_prefix("fire:") {
+            if serial.is_empty() || !serial.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            Ok(DeviceId::Serial(serial.to_ascii_lowercase().into()))
+        } else if let Some(index) = s.strip_prefix("fire#") {
+            index.parse().map(DeviceId::Enumerated).map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+/// What a device told us about itself in reply to a universal identity
+/// request.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    /// One byte ids like Akai's 0x47 are stored as-is, three byte ids as
+    /// 0x00xxyy.
+    pub manufacturer: u32,
+    pub family: u16,
+    pub model: u16,
+    /// The firmware version, as reported.
+    pub version: [u8; 4],
+    /// Whatever the device sends after the version, which for the Fire is its
+    /// serial number.  Empty if the device doesn't send one.
+    pub serial: Vec<u8>,
+}
+
+impl DeviceIdentity {
+    /// Parses a universal non-realtime identity reply:
+    /// `f0 7e <device> 06 02 <manufacturer> <family:2> <model:2> <version:4>
+    /// [serial...] f7`.
+    pub fn parse(sysex: &[u8]) -> Option<Self> {
+        let body = match sysex {
+            [0xf0, 0x7e, _, 0x06, 0x02, body @ .., 0xf7] => body,
+            _ => return None,
+        };
+        let (manufacturer, rest) = match body {
+            [0x00, hi, lo, rest @ ..] => (((*hi as u32) << 8) | *lo as u32, rest),
+            [id, rest @ ..] => (*id as u32, rest),
+            [] => return None,
+        };
+        match rest {
+            [f0, f1, m0, m1, v0, v1, v2, v3, serial @ ..] => Some(DeviceIdentity {
+                manufacturer,
+                family: (*f0 as u16) | ((*f1 as u16) << 7),
+                model: (*m0 as u16) | ((*m1 as u16) << 7),
+                version: [*v0, *v1, *v2, *v3],
+                serial: serial.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The stable id for the device, if it reported a serial number.
+    pub fn device_id(&self) -> Option<DeviceId> {
+        if self.serial.is_empty() {
+            return None;
+        }
+        let hex: String = self.serial.iter().map(|b| format!("{:02x}", b)).collect();
+        Some(DeviceId::Serial(hex.into()))
+    }
+}
@@ -0,0 +1,356 @@
+use crate::calibration::CalibrationStore;
+use crate::device::{DeviceId, DeviceIdentity};
+use crate::error::Result;
+use crate::queue::OverflowPolicy;
+use crate::transport::{MidiTransport, MidirTransport};
+
+// These get reported like so on Linux:
+// FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 32:0
+// FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 36:0
+//
+// And on Windows:
+// FL STUDIO FIRE
+// FL STUDIO FIRE
+pub const MIDI_INPUT_PORT_PREFIX: &str = "FL STUDIO FIRE"; //:FL STUDIO FIRE MIDI 1 ";
+pub const MIDI_OUTPUT_PORT_PREFIX: &str = "FL STUDIO FIRE"; //:FL STUDIO FIRE MIDI 1 ";
+
+/// The input and output ports belonging to a single Fire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirePorts {
+    pub input: usize,
+    pub output: usize,
+    pub name: String,
+}
+
+/// Decides which port names belong to Fire controllers.
+#[derive(Clone, Debug)]
+pub enum PortMatcher {
+    Prefix(String),
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl PortMatcher {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            PortMatcher::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            #[cfg(feature = "regex")]
+            PortMatcher::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+/// Picks out particular devices for `DiscoveryOptions::include` and
+/// `DiscoveryOptions::exclude`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceFilter {
+    /// The serial number from the identity reply, in hex as it appears in
+    /// `DeviceId::Serial`.
+    Serial(String),
+    /// The exact name of the device's input port.
+    PortName(String),
+}
+
+impl DeviceFilter {
+    fn matches(&self, port_name: &str, identity: Option<&DeviceIdentity>) -> bool {
+        match self {
+            DeviceFilter::PortName(name) => name == port_name,
+            DeviceFilter::Serial(serial) => {
+                match identity.and_then(|i| i.device_id()) {
+                    Some(DeviceId::Serial(s)) => *serial == *s,
+                    _ => false,
+                }
+            },
+        }
+    }
+}
+
+/// Controls which devices get attached and how.
+///
+/// The defaults match every port starting with "FL STUDIO FIRE", use the
+/// midir client names "Fire-Walk" and "Fire", and leave the device showing
+/// whatever it was showing.
+#[derive(Clone, Debug)]
+pub struct DiscoveryOptions {
+    input_matcher: PortMatcher,
+    output_matcher: PortMatcher,
+    input_client_name: String,
+    output_client_name: String,
+    include: Vec<DeviceFilter>,
+    exclude: Vec<DeviceFilter>,
+    channel_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    clear_on_attach: bool,
+    calibrations: CalibrationStore,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            input_matcher: PortMatcher::Prefix(MIDI_INPUT_PORT_PREFIX.to_string()),
+            output_matcher: PortMatcher::Prefix(MIDI_OUTPUT_PORT_PREFIX.to_string()),
+            input_client_name: "Fire-Walk".to_string(),
+            output_client_name: "Fire".to_string(),
+            include: vec![],
+            exclude: vec![],
+            channel_capacity: 100,
+            overflow_policy: OverflowPolicy::DropNewest,
+            clear_on_attach: false,
+            calibrations: CalibrationStore::new(),
+        }
+    }
+}
+
+impl DiscoveryOptions {
+    pub fn new() -> Self {
+        DiscoveryOptions::default()
+    }
+
+    /// Sets how input and output port names are recognized.
+    pub fn with_matchers(mut self, input: PortMatcher, output: PortMatcher) -> Self {
+        self.input_matcher = input;
+        self.output_matcher = output;
+        self
+    }
+
+    /// Sets the client names the midir transport registers with the OS.
+    pub fn with_client_names(mut self, input: &str, output: &str) -> Self {
+        self.input_client_name = input.to_string();
+        self.output_client_name = output.to_string();
+        self
+    }
+
+    /// Only attach devices matching at least one included filter.  With no
+    /// included filters, every device is a candidate.
+    pub fn include(mut self, filter: DeviceFilter) -> Self {
+        self.include.push(filter);
+        self
+    }
+
+    /// Never attach devices matching the filter.
+    pub fn exclude(mut self, filter: DeviceFilter) -> Self {
+        self.exclude.push(filter);
+        self
+    }
+
+    /// Sets how many events from the device each controller's channel holds.
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Sets what happens to events from a device when its channel is full.
+    /// The default is `OverflowPolicy::DropNewest`.
+    pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Sets whether to blank the pads, button LEDs and OLED on attach.
+    pub fn with_clear_on_attach(mut self, clear: bool) -> Self {
+        self.clear_on_attach = clear;
+        self
+    }
+
+    /// Sets the calibration profiles that controllers pick theirs from on
+    /// attach.
+    pub fn with_calibrations(mut self, calibrations: CalibrationStore) -> Self {
+        self.calibrations = calibrations;
+        self
+    }
+
+    pub fn channel_capacity(&self) -> usize {
+        self.channel_capacity
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    pub fn clear_on_attach(&self) -> bool {
+        self.clear_on_attach
+    }
+
+    pub fn calibrations(&self) -> &CalibrationStore {
+        &self.calibrations
+    }
+
+    /// The midir transport using the configured client names.
+    pub fn midir_transport(&self) -> MidirTransport {
+        MidirTransport::new(&self.input_client_name, &self.output_client_name)
+    }
+
+    /// Whether the device on the given port with the given identity should be
+    /// attached.  Serial filters only match devices that told us their serial,
+    /// which requires briefly connecting to them.
+    pub fn accepts(&self, port_name: &str, identity: Option<&DeviceIdentity>) -> bool {
+        let included = self.include.is_empty() ||
+            self.include.iter().any(|f| f.matches(port_name, identity));
+        included && !self.exclude.iter().any(|f| f.matches(port_name, identity))
+    }
+
+    /// Whether `accepts` needs to know the device's identity to decide.
+    pub fn needs_identity(&self) -> bool {
+        self.include.iter().chain(self.exclude.iter()).any(|f| match f {
+            DeviceFilter::Serial(_) => true,
+            DeviceFilter::PortName(_) => false,
+        })
+    }
+
+    /// Whether the port is excluded by name, in which case there's no need to
+    /// connect to it at all.
+    fn excludes_port(&self, port_name: &str) -> bool {
+        self.exclude.iter().any(|f| match f {
+            DeviceFilter::PortName(name) => name == port_name,
+            DeviceFilter::Serial(_) => false,
+        })
+    }
+}
+
+/// Finds the ports of every Fire controller known to the transport, leaving
+/// out the ones excluded by name.
+pub fn find_fire_ports(transport: &dyn MidiTransport, options: &DiscoveryOptions)
+    -> Result<Vec<FirePorts>> {
+    // Accumulate the lists of ports completely first so that everything is
+    // paired against the same snapshot.
+    let input_names = transport.input_port_names()?;
+    let output_names = transport.output_port_names()?;
+    let mut ports = pair_ports(&input_names, &output_names, options);
+    ports.retain(|p| !options.excludes_port(&p.name));
+    Ok(ports)
+}
+
+/// Splits the ALSA "client:port" suffix off a Linux port name, returning the
+/// rest of the name and the client id.
+fn split_alsa_suffix(name: &str) -> Option<(&str, u32)> {
+    let space = name.rfind(' ')?;
+    let (base, suffix) = (&name[..space], &name[space + 1..]);
+    let colon = suffix.find(':')?;
+    let client = suffix[..colon].parse().ok()?;
+    suffix[colon + 1..].parse::<u32>().ok()?;
+    Some((base, client))
+}
+
+/// Pairs up the input and output ports that belong to the same Fire, given
+/// the names of all the input and output ports in index order and the
+/// options' port matchers.  Input ports without an output port are skipped.
+///
+/// On Linux, the ALSA port names end in "client:port" and the two sides of a
+/// device share the client id while the port number may differ, so ports are
+/// paired by client id:
+///
+/// ```text
+/// FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 32:0
+/// ```
+///
+/// On Windows every Fire is just "FL STUDIO FIRE" on both sides, so the nth
+/// input port with a given name is paired with the nth output port with that
+/// name.  The OS enumerates both sides of the devices in the same order.
+///
+/// Matchers can also pick out devices whose input and output ports are named
+/// differently.  Then the nth input port whose name no matching output port
+/// has is paired with the nth output port whose name no matching input port
+/// has.
+pub fn pair_ports(input_names: &[String], output_names: &[String], options: &DiscoveryOptions)
+    -> Vec<FirePorts> {
+    let inputs: Vec<(usize, &String)> = input_names.iter().enumerate()
+        .filter(|(_, name)| options.input_matcher.matches(name))
+        .collect();
+    let outputs: Vec<(usize, &String)> = output_names.iter().enumerate()
+        .filter(|(_, name)| options.output_matcher.matches(name))
+        .collect();
+    let mut taken = vec![false; outputs.len()];
+    let unnamed_alike = |name: &str, others: &[(usize, &String)]| {
+        split_alsa_suffix(name).is_none() && !others.iter().any(|(_, other)| *other == name)
+    };
+
+    let mut pairs = vec![];
+    for &(input, name) in &inputs {
+        let found = match split_alsa_suffix(name) {
+            Some((base, client)) => outputs.iter().enumerate().position(|(j, (_, o))| {
+                !taken[j] && split_alsa_suffix(o) == Some((base, client))
+            }),
+            None if unnamed_alike(name, &outputs) => {
+                outputs.iter().enumerate().position(|(j, (_, o))| {
+                    !taken[j] && unnamed_alike(o, &inputs)
+                })
+            },
+            None => outputs.iter().enumerate().position(|(j, (_, o))| {
+                !taken[j] && *o == name
+            }),
+        };
+        if let Some(j) = found {
+            taken[j] = true;
+            pairs.push(FirePorts {
+                input,
+                output: outputs[j].0,
+                name: name.clone(),
+            });
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn ports(input: usize, output: usize, name: &str) -> FirePorts {
+        FirePorts { input, output, name: name.to_string() }
+    }
+
+    fn pair(inputs: &[&str], outputs: &[&str]) -> Vec<FirePorts> {
+        pair_ports(&names(inputs), &names(outputs), &DiscoveryOptions::default())
+    }
+
+    #[test]
+    fn windows_names_pair_in_order() {
+        let fire = "FL STUDIO FIRE";
+        assert_eq!(pair(&[fire, fire, fire], &[fire, fire, fire]),
+                   vec![ports(0, 0, fire), ports(1, 1, fire), ports(2, 2, fire)]);
+    }
+
+    #[test]
+    fn alsa_names_pair_by_client() {
+        let a_in = "FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 32:0";
+        let b_in = "FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 36:0";
+        let a_out = "FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 32:1";
+        let b_out = "FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 36:1";
+        assert_eq!(pair(&[a_in, b_in], &[b_out, a_out]),
+                   vec![ports(0, 1, a_in), ports(1, 0, b_in)]);
+    }
+
+    #[test]
+    fn other_gear_is_skipped() {
+        let fire = "FL STUDIO FIRE";
+        let inputs = ["Midi Through", fire, "Launchpad", fire];
+        let outputs = ["Launchpad", fire, "Midi Through", "Synth", fire];
+        assert_eq!(pair(&inputs, &outputs), vec![ports(1, 1, fire), ports(3, 4, fire)]);
+    }
+
+    #[test]
+    fn input_without_output_is_skipped() {
+        let a_in = "FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 32:0";
+        let b_in = "FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 36:0";
+        let b_out = "FL STUDIO FIRE:FL STUDIO FIRE MIDI 1 36:0";
+        assert_eq!(pair(&[a_in, b_in], &[b_out]), vec![ports(1, 0, b_in)]);
+
+        let fire = "FL STUDIO FIRE";
+        assert_eq!(pair(&[fire, fire], &[fire]), vec![ports(0, 0, fire)]);
+        assert_eq!(pair(&[fire], &[]), vec![]);
+    }
+
+    #[test]
+    fn differently_named_sides_pair_in_order() {
+        let options = DiscoveryOptions::default().with_matchers(
+            PortMatcher::Prefix("Fire In".into()), PortMatcher::Prefix("Fire Out".into()));
+        let inputs = names(&["Fire In A", "Synth", "Fire In B"]);
+        let outputs = names(&["Fire Out A", "Fire Out B", "Synth"]);
+        assert_eq!(pair_ports(&inputs, &outputs, &options),
+                   vec![ports(0, 0, "Fire In A"), ports(2, 1, "Fire In B")]);
+    }
+}
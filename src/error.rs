@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can go wrong talking to a Fire controller.
+///
+/// The MIDI backend's own errors are flattened into strings so that the error
+/// stays `Send`, `Clone` and independent of the backend in use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FireError {
+    /// The MIDI system couldn't be initialized or its ports couldn't be listed.
+    PortEnumeration(String),
+    /// The named port disappeared or connecting to it failed.
+    Connect { port: String, reason: String },
+    /// A message couldn't be sent to the device.
+    Send(String),
+    /// The controller's event channel was full so events had to be dropped.
+    ChannelOverflow,
+    /// The controller isn't currently connected.
+    NotConnected,
+    /// A caller-supplied argument was out of range.
+    InvalidArgument(String),
+}
+
+pub type Result<T> = std::result::Result<T, FireError>;
+
+impl fmt::Display for FireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FireError::PortEnumeration(reason) =>
+                write!(f, "failed to enumerate MIDI ports: {}", reason),
+            FireError::Connect { port, reason } =>
+                write!(f, "failed to connect to port {:?}: {}", port, reason),
+            FireError::Send(reason) =>
+                write!(f, "failed to send MIDI message: {}", reason),
+            FireError::ChannelOverflow =>
+                write!(f, "event channel overflowed, events were dropped"),
+            FireError::NotConnected =>
+                write!(f, "controller is not connected"),
+            FireError::InvalidArgument(what) =>
+                write!(f, "invalid argument: {}", what),
+        }
+    }
+}
+
+impl Error for FireError {}
+
+impl From<midir::InitError> for FireError {
+    fn from(err: midir::InitError) -> Self {
+        FireError::PortEnumeration(err.to_string())
+    }
+}
+
+impl From<midir::PortInfoError> for FireError {
+    fn from(err: midir::PortInfoError) -> Self {
+        FireError::PortEnumeration(err.to_string())
+    }
+}
+
+impl From<midir::SendError> for FireError {
+    fn from(err: midir::SendError) -> Self {
+        FireError::Send(err.to_string())
+    }
+}
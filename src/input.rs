@@ -0,0 +1,292 @@
+use std::time::Instant;
+
+use crate::device::DeviceId;
+
+/// Controller Buttons, Left-to-right, Top-to-bottom, first non-shifted label
+/// associated with the button except for the grid row buttons.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ControllerButton {
+    // ## Top Row
+    // "Channel"/"Mixer"/"User 1"/"User 2"
+    Channel,
+    PatternUp,
+    PatternDown,
+    /// Pushing the Select knob.
+    SelectPress,
+    Browser,
+    GridLeft,
+    GridRight,
+    // ## The Grid Row Buttons ("Mute"/"Solo")
+    Row1,
+    Row2,
+    Row3,
+    Row4,
+    // ## Bottom Row
+    Step,
+    Note,
+    Drum,
+    Perform,
+    Shift,
+    Alt,
+    Pattern,
+    Play,
+    Stop,
+    Record,
+}
+
+/// The note number each button reports itself with, in the same order as the
+/// `ControllerButton` declaration.  Button LEDs are addressed by CC messages
+/// using the same number.
+///
+/// The Select knob's push shares its number with the knob itself, see
+/// `ControllerEvent::from_midi`.
+///
+/// The gaps in the 0x1a..=0x35 range aren't buttons: 0x1b is the
+/// Channel/Mixer/User mode indicator LED, 0x1c..=0x1e are unused, and
+/// 0x28..=0x2b are the LEDs next to the grid row buttons.  Thanks to the
+/// Segger write-up for the layout.
+const BUTTON_NOTES: [(ControllerButton, u8); 21] = [
+    (ControllerButton::Channel, 0x1a),
+    (ControllerButton::PatternUp, 0x1f),
+    (ControllerButton::PatternDown, 0x20),
+    (ControllerButton::SelectPress, 0x19),
+    (ControllerButton::Browser, 0x21),
+    (ControllerButton::GridLeft, 0x22),
+    (ControllerButton::GridRight, 0x23),
+    (ControllerButton::Row1, 0x24),
+    (ControllerButton::Row2, 0x25),
+    (ControllerButton::Row3, 0x26),
+    (ControllerButton::Row4, 0x27),
+    (ControllerButton::Step, 0x2c),
+    (ControllerButton::Note, 0x2d),
+    (ControllerButton::Drum, 0x2e),
+    (ControllerButton::Perform, 0x2f),
+    (ControllerButton::Shift, 0x30),
+    (ControllerButton::Alt, 0x31),
+    (ControllerButton::Pattern, 0x32),
+    (ControllerButton::Play, 0x33),
+    (ControllerButton::Stop, 0x34),
+    (ControllerButton::Record, 0x35),
+];
+
+impl ControllerButton {
+    /// Every button, in declaration order.
+    pub fn all() -> impl Iterator<Item = ControllerButton> {
+        BUTTON_NOTES.iter().map(|&(button, _)| button)
+    }
+
+    /// The button that reports itself with the given note number, if any.
+    pub fn from_note(note: u8) -> Option<Self> {
+        BUTTON_NOTES.iter().find(|&&(_, n)| n == note).map(|&(button, _)| button)
+    }
+
+    /// The note number the button reports itself with, which is also the CC
+    /// number that addresses its LED.
+    pub fn note(self) -> u8 {
+        // The table is in declaration order, so the discriminant indexes it.
+        BUTTON_NOTES[self as usize].1
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ControllerKnob {
+    Volume,
+    Pan,
+    Filter,
+    Resonance,
+    Select,
+}
+
+/// The modes shown by the indicator LEDs next to the Channel button, in the
+/// order pressing it cycles through them.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ChannelMode {
+    Channel,
+    Mixer,
+    User1,
+    User2,
+}
+
+impl ChannelMode {
+    /// The mode after this one, wrapping around.
+    pub fn next(self) -> Self {
+        match self {
+            ChannelMode::Channel => ChannelMode::Mixer,
+            ChannelMode::Mixer => ChannelMode::User1,
+            ChannelMode::User1 => ChannelMode::User2,
+            ChannelMode::User2 => ChannelMode::Channel,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ButtonState {
+    Down,
+    Up
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ControllerEvent {
+    ControlButton(ControllerButton, ButtonState),
+    /// A knob was turned by the given number of detents.  Positive is
+    /// clockwise.  Faster turns report larger magnitudes.
+    KnobTurn(ControllerKnob, i8),
+    /// A turn of the Volume, Pan, Filter or Resonance knob while mode cycling
+    /// is on, see `FireController::set_mode_cycling`, reported under the mode
+    /// that was current.  The Select knob keeps reporting `KnobTurn`.
+    LayerKnobTurn(ChannelMode, ControllerKnob, i8),
+    /// A capacitive knob was touched or released.  Only the Volume, Pan,
+    /// Filter and Resonance knobs have touch sensors; pushing the Select knob
+    /// is reported as `ControllerButton::SelectPress` instead.
+    KnobTouch(ControllerKnob, ButtonState),
+    /// A grid button changed state.  (index, row0, col0, velocity)
+    GridButton(u8, u8, u8, ButtonState, u8),
+    /// A note in the labeled button range that doesn't correspond to any known
+    /// button.  (note, state)
+    UnknownButton(u8, ButtonState),
+    /// Bytes from the device that couldn't be interpreted, such as stray data
+    /// bytes, an interrupted sysex or an unexpected kind of message.
+    Unparsed(Vec<u8>),
+    /// The controller (re)connected to its device.  Not sent by the device.
+    Connected,
+    /// The controller lost its device.  Not sent by the device.
+    Disconnected,
+    /// Mode cycling switched to the given mode after a press of the Channel
+    /// button.  Not sent by the device.
+    ModeChanged(ChannelMode),
+}
+
+/// A `ControllerEvent` along with where and when it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventEnvelope {
+    /// The controller that sent the event.
+    pub device: DeviceId,
+    /// The MIDI backend's timestamp for the message in microseconds.  Only
+    /// differences between timestamps from the same device are meaningful.
+    /// Zero for events that didn't come from the device.
+    pub stamp: u64,
+    /// When the message was received by the MIDI input callback.
+    pub received: Instant,
+    pub event: ControllerEvent,
+}
+
+impl ControllerEvent {
+    /// Interprets a single complete three-byte channel message.  The channel is
+    /// ignored and a note-on with velocity 0 is treated as a note-off.  Use
+    /// `MidiParser` to handle arbitrary byte streams.
+    pub fn from_midi(msg: &[u8]) -> Option<Self> {
+        match *msg {
+            [status, data1, data2] => {
+                let kind = match (status & 0xf0, data2) {
+                    (0x90, 0) => 0x80,
+                    (kind, _) => kind,
+                };
+                ControllerEvent::from_channel_message(kind, data1, data2)
+            },
+            _ => None,
+        }
+    }
+
+    fn from_channel_message(kind: u8, data1: u8, data2: u8) -> Option<Self> {
+        match (kind, data1, data2) {
+            // ## Knobs!
+            (0xb0,  kn @ (0x10..=0x13 | 0x19), value) => {
+                let knob = match kn {
+                    0x10 => ControllerKnob::Volume,
+                    0x11 => ControllerKnob::Pan,
+                    0x12 => ControllerKnob::Filter,
+                    0x13 => ControllerKnob::Resonance,
+                    0x19 => ControllerKnob::Select,
+                    _ => unreachable!(),
+                };
+                Some(ControllerEvent::KnobTurn(knob, decode_knob_delta(value)))
+            },
+            // ## Knob touches!  Notes on 0x10..=0x13 come from the touch
+            // sensors of the four capacitive knobs.  The Select knob has
+            // no touch sensor; a note on 0x19 is it being pushed, which is
+            // handled with the labeled buttons below.
+            (ud @ (0x90 | 0x80),  kn @ 0x10..=0x13, _) => {
+                let knob = match kn {
+                    0x10 => ControllerKnob::Volume,
+                    0x11 => ControllerKnob::Pan,
+                    0x12 => ControllerKnob::Filter,
+                    0x13 => ControllerKnob::Resonance,
+                    _ => unreachable!(),
+                };
+                match ud {
+                    0x90 => Some(ControllerEvent::KnobTouch(knob, ButtonState::Down)),
+                    0x80 => Some(ControllerEvent::KnobTouch(knob, ButtonState::Up)),
+                    _ => unreachable!(),
+                }
+            },
+            // ## Labeled Buttons!
+            (ud @ (0x90 | 0x80),  btn @ 0x19..=0x35, _) => {
+                let state = match ud {
+                    0x90 => ButtonState::Down,
+                    0x80 => ButtonState::Up,
+                    _ => unreachable!(),
+                };
+                match ControllerButton::from_note(btn) {
+                    Some(button) => Some(ControllerEvent::ControlButton(button, state)),
+                    None => Some(ControllerEvent::UnknownButton(btn, state)),
+                }
+            },
+            // ## The grid (pads)!
+            (ud @ (0x90 | 0x80),  btn @ 0x36..=0x75, vel) => {
+                let state = match ud {
+                    0x90 => ButtonState::Down,
+                    0x80 => ButtonState::Up,
+                    _ => unreachable!(),
+                };
+                let index = btn - 0x36;
+                let row0 = index / 16;
+                let col0 = index % 16;
+                Some(ControllerEvent::GridButton(index, row0, col0, state, vel))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The knobs are endless encoders that report 7-bit two's complement deltas:
+/// 0x01..=0x3f are clockwise, 0x41..=0x7f counter-clockwise.
+pub fn decode_knob_delta(value: u8) -> i8 {
+    // Shift the 7-bit sign bit into the i8 sign bit and back to extend it.
+    ((value << 1) as i8) >> 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_code_in_the_button_range_resolves() {
+        for note in 0x1a..=0x35u8 {
+            for &(status, state) in &[(0x90, ButtonState::Down), (0x80, ButtonState::Up)] {
+                let event = ControllerEvent::from_midi(&[status, note, 0x7f]).unwrap();
+                let expected = match ControllerButton::from_note(note) {
+                    Some(button) => ControllerEvent::ControlButton(button, state),
+                    None => ControllerEvent::UnknownButton(note, state),
+                };
+                assert_eq!(event, expected, "note {:#x}", note);
+                let gap = (0x1b..=0x1e).contains(&note) || (0x28..=0x2b).contains(&note);
+                assert_eq!(ControllerButton::from_note(note).is_none(), gap, "note {:#x}", note);
+            }
+        }
+    }
+
+    #[test]
+    fn select_push_is_a_button() {
+        assert_eq!(ControllerEvent::from_midi(&[0x90, 0x19, 0x7f]),
+                   Some(ControllerEvent::ControlButton(ControllerButton::SelectPress,
+                                                       ButtonState::Down)));
+    }
+
+    #[test]
+    fn notes_round_trip() {
+        assert_eq!(ControllerButton::all().count(), BUTTON_NOTES.len());
+        for button in ControllerButton::all() {
+            assert_eq!(ControllerButton::from_note(button.note()), Some(button));
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Turning the knobs' relative deltas into bounded absolute values.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::input::{ChannelMode, ControllerEvent, ControllerKnob};
+
+/// Speeds up value changes when a knob is turned quickly.
+///
+/// Turns arriving within `window` of the previous turn have their step
+/// multiplied by a factor that ramps linearly from 1 (at `window`) up to
+/// `max_multiplier` (back-to-back turns).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Acceleration {
+    pub window: Duration,
+    pub max_multiplier: f64,
+}
+
+impl Acceleration {
+    fn multiplier(&self, since_last: Duration) -> f64 {
+        if since_last >= self.window || self.window == Duration::from_secs(0) {
+            return 1.0;
+        }
+        let closeness = 1.0 - since_last.as_secs_f64() / self.window.as_secs_f64();
+        1.0 + (self.max_multiplier - 1.0).max(0.0) * closeness
+    }
+}
+
+/// Accumulates a single knob's deltas into a value clamped to `min..=max`.
+#[derive(Clone, Debug)]
+pub struct KnobAccumulator {
+    value: f64,
+    min: f64,
+    max: f64,
+    step: f64,
+    acceleration: Option<Acceleration>,
+    last_turn: Option<Instant>,
+}
+
+impl KnobAccumulator {
+    /// Creates an accumulator over `min..=max` starting at `initial` that moves
+    /// by 1 per detent without acceleration.
+    pub fn new(min: f64, max: f64, initial: f64) -> Self {
+        let (min, max) = if min <= max { (min, max) } else { (max, min) };
+        KnobAccumulator {
+            value: initial.max(min).min(max),
+            min,
+            max,
+            step: 1.0,
+            acceleration: None,
+            last_turn: None,
+        }
+    }
+
+    /// Sets how far a single detent moves the value.
+    pub fn with_step(mut self, step: f64) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn with_acceleration(mut self, acceleration: Acceleration) -> Self {
+        self.acceleration = Some(acceleration);
+        self
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn set_value(&mut self, value: f64) {
+        self.value = value.max(self.min).min(self.max);
+    }
+
+    /// Applies a turn happening now, returning the new value.
+    pub fn apply(&mut self, delta: i8) -> f64 {
+        self.apply_at(delta, Instant::now())
+    }
+
+    /// Applies a turn that happened at `when`, returning the new value.
+    pub fn apply_at(&mut self, delta: i8, when: Instant) -> f64 {
+        let multiplier = match (self.acceleration, self.last_turn) {
+            (Some(accel), Some(last)) => accel.multiplier(when.saturating_duration_since(last)),
+            _ => 1.0,
+        };
+        self.last_turn = Some(when);
+        self.set_value(self.value + delta as f64 * self.step * multiplier);
+        self.value
+    }
+}
+
+/// Optional per-knob accumulators fed straight from the event stream.
+///
+/// With mode cycling on, knob turns arrive as `ControllerEvent::LayerKnobTurn`
+/// and each layer can have accumulators of its own.  Turns in a layer without
+/// one for the knob go to the knob's plain accumulator, if any.
+#[derive(Clone, Debug, Default)]
+pub struct KnobAccumulators {
+    knobs: HashMap<(Option<ChannelMode>, ControllerKnob), KnobAccumulator>,
+}
+
+impl KnobAccumulators {
+    pub fn new() -> Self {
+        KnobAccumulators::default()
+    }
+
+    /// Starts accumulating turns of `knob`, replacing any prior accumulator.
+    pub fn insert(&mut self, knob: ControllerKnob, accumulator: KnobAccumulator) {
+        self.knobs.insert((None, knob), accumulator);
+    }
+
+    /// Starts accumulating turns of `knob` in the `layer` knob layer,
+    /// replacing any prior accumulator for it.
+    pub fn insert_layer(&mut self, layer: ChannelMode, knob: ControllerKnob,
+                        accumulator: KnobAccumulator) {
+        self.knobs.insert((Some(layer), knob), accumulator);
+    }
+
+    pub fn get(&self, knob: ControllerKnob) -> Option<&KnobAccumulator> {
+        self.knobs.get(&(None, knob))
+    }
+
+    pub fn get_mut(&mut self, knob: ControllerKnob) -> Option<&mut KnobAccumulator> {
+        self.knobs.get_mut(&(None, knob))
+    }
+
+    pub fn get_layer(&self, layer: ChannelMode, knob: ControllerKnob)
+        -> Option<&KnobAccumulator> {
+        self.knobs.get(&(Some(layer), knob))
+    }
+
+    pub fn get_layer_mut(&mut self, layer: ChannelMode, knob: ControllerKnob)
+        -> Option<&mut KnobAccumulator> {
+        self.knobs.get_mut(&(Some(layer), knob))
+    }
+
+    /// Feeds an event through, returning the knob and its new value if the
+    /// event turned a knob with an accumulator.
+    pub fn handle(&mut self, event: &ControllerEvent) -> Option<(ControllerKnob, f64)> {
+        let (layer, knob, delta) = match *event {
+            ControllerEvent::KnobTurn(knob, delta) => (None, knob, delta),
+            ControllerEvent::LayerKnobTurn(layer, knob, delta) => (Some(layer), knob, delta),
+            _ => return None,
+        };
+        let key = if self.knobs.contains_key(&(layer, knob)) {
+            (layer, knob)
+        } else {
+            (None, knob)
+        };
+        let acc = self.knobs.get_mut(&key)?;
+        Some((knob, acc.apply(delta)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::DiscoveryOptions;
+    use crate::mock::TestFire;
+
+    #[test]
+    fn deltas_are_sign_extended() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.feed(&[0xb0, 0x10, 0x01, 0xb0, 0x11, 0x7f, 0xb0, 0x12, 0x3f, 0xb0, 0x13, 0x41,
+                    0xb0, 0x19, 0x40]);
+        assert_eq!(fire.events(), vec![
+            ControllerEvent::KnobTurn(ControllerKnob::Volume, 1),
+            ControllerEvent::KnobTurn(ControllerKnob::Pan, -1),
+            ControllerEvent::KnobTurn(ControllerKnob::Filter, 63),
+            ControllerEvent::KnobTurn(ControllerKnob::Resonance, -63),
+            ControllerEvent::KnobTurn(ControllerKnob::Select, -64),
+        ]);
+    }
+
+    #[test]
+    fn accumulators_follow_the_event_stream() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        let mut knobs = KnobAccumulators::new();
+        knobs.insert(ControllerKnob::Volume, KnobAccumulator::new(0.0, 10.0, 5.0).with_step(2.0));
+        fire.feed(&[0xb0, 0x10, 0x01, 0xb0, 0x11, 0x01, 0xb0, 0x10, 0x02, 0xb0, 0x10, 0x7d]);
+        let results: Vec<_> = fire.events().iter().map(|event| knobs.handle(event)).collect();
+        assert_eq!(results, vec![
+            Some((ControllerKnob::Volume, 7.0)),
+            None,
+            // Clamped.
+            Some((ControllerKnob::Volume, 10.0)),
+            Some((ControllerKnob::Volume, 4.0)),
+        ]);
+        assert_eq!(knobs.get(ControllerKnob::Volume).unwrap().value(), 4.0);
+    }
+
+    #[test]
+    fn layers_have_their_own_accumulators() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.controller.set_mode_cycling(true).unwrap();
+        let mut knobs = KnobAccumulators::new();
+        knobs.insert(ControllerKnob::Volume, KnobAccumulator::new(0.0, 100.0, 0.0));
+        knobs.insert_layer(ChannelMode::Mixer, ControllerKnob::Volume,
+                           KnobAccumulator::new(0.0, 100.0, 50.0));
+        // Channel layer, then Mixer after pressing Channel.
+        fire.feed(&[0xb0, 0x10, 0x01, 0x90, 0x1a, 0x7f, 0x80, 0x1a, 0x00, 0xb0, 0x10, 0x02]);
+        let results: Vec<_> = fire.events().iter().filter_map(|event| knobs.handle(event))
+            .collect();
+        assert_eq!(results, vec![(ControllerKnob::Volume, 1.0), (ControllerKnob::Volume, 52.0)]);
+        assert_eq!(knobs.get(ControllerKnob::Volume).unwrap().value(), 1.0);
+        assert_eq!(knobs.get_layer(ChannelMode::Mixer, ControllerKnob::Volume).unwrap().value(),
+                   52.0);
+    }
+
+    #[test]
+    fn quick_turns_accelerate() {
+        let acceleration = Acceleration {
+            window: Duration::from_millis(100),
+            max_multiplier: 5.0,
+        };
+        let mut knob = KnobAccumulator::new(-1000.0, 1000.0, 0.0).with_acceleration(acceleration);
+        let start = Instant::now();
+        assert_eq!(knob.apply_at(1, start), 1.0);
+        // Back to back.
+        assert_eq!(knob.apply_at(1, start), 6.0);
+        // Halfway through the window.
+        assert_eq!(knob.apply_at(1, start + Duration::from_millis(50)), 9.0);
+        // Past it.
+        assert_eq!(knob.apply_at(-1, start + Duration::from_secs(1)), 8.0);
+    }
+}
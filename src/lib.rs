@@ -0,0 +1,69 @@
+//! Rust binding for the Akai Fire controller with special attention paid to
+//! supporting multiple devices.
+//!
+//! - `input`: Parsing of MIDI messages from the device into `ControllerEvent`s.
+//! - `parser`: Splitting the raw MIDI byte stream into messages.
+//! - `queue`: The event channel and what happens when it overflows.
+//! - `knob`: Accumulating the knobs' relative turns into absolute values.
+//! - `command`: Encoding and decoding the messages we send to the device.
+//! - `brightness`: Dimming the device, as a whole or by region.
+//! - `calibration`: Correcting for differences between devices' LEDs.
+//! - `color`: Screen colors and how to show them on the pads.
+//! - `output`: Tracking what the device's LEDs should show.
+//! - `oled`: Drawing to the OLED.
+//! - `shadow`: Tracking what the device is showing so it can be restored.
+//! - `device`: Identifying controllers.
+//! - `discovery`: Finding the MIDI ports that belong to Fire controllers.
+//! - `controller`: Connecting to the devices and managing those connections.
+//! - `writer`: Queueing commands for a device from any thread.
+//! - `manager`: Keeping controllers attached as devices come and go.
+//! - `transport`: The MIDI backend abstraction, with `midir` as the default.
+//! - `mock`: An in-memory transport for testing without hardware.
+
+extern crate midir;
+extern crate tokio;
+
+pub mod brightness;
+pub mod calibration;
+pub mod color;
+pub mod command;
+pub mod controller;
+pub mod device;
+pub mod discovery;
+pub mod error;
+pub mod input;
+pub mod knob;
+pub mod manager;
+pub mod mock;
+pub mod oled;
+pub mod output;
+pub mod parser;
+pub mod queue;
+pub mod shadow;
+pub mod transport;
+pub mod writer;
+
+pub use brightness::LedRegion;
+pub use calibration::{Calibration, CalibrationPage, CalibrationSession, CalibrationStore};
+pub use color::Color;
+pub use command::{ControllerCommand, PadColor};
+pub use controller::FireController;
+pub use device::{DeviceId, DeviceIdentity};
+pub use discovery::{
+    DeviceFilter, DiscoveryOptions, FirePorts, PortMatcher, MIDI_INPUT_PORT_PREFIX,
+    MIDI_OUTPUT_PORT_PREFIX,
+};
+pub use error::{FireError, Result};
+pub use input::{
+    ButtonState, ChannelMode, ControllerButton, ControllerEvent, ControllerKnob, EventEnvelope,
+};
+pub use knob::{Acceleration, KnobAccumulator, KnobAccumulators};
+pub use manager::{Envelopes, FireManager};
+pub use mock::MockTransport;
+pub use oled::OledBitmap;
+pub use output::{ButtonLedState, PadLedBuffer};
+pub use parser::{MidiParser, ParsedMessage};
+pub use queue::{EventReceiver, OverflowPolicy};
+pub use shadow::DeviceShadow;
+pub use transport::{MidiTransport, MidirTransport};
+pub use writer::{FrameStats, OutputHandle};
@@ -0,0 +1,479 @@
+//! Keeping track of controllers as devices get plugged in and out.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::stream::{Stream, StreamMap};
+use tokio::task::JoinHandle;
+use tokio::time::Interval;
+
+use crate::controller::{probe_identity, FireController};
+use crate::device::{DeviceId, DeviceIdentity};
+use crate::discovery::{find_fire_ports, DiscoveryOptions, FirePorts};
+use crate::error::{FireError, Result};
+use crate::input::{ControllerEvent, EventEnvelope};
+use crate::queue::EventReceiver;
+use crate::transport::MidiTransport;
+
+/// Owns every controller ever attached and keeps them connected to their
+/// devices.
+///
+/// Call `scan` periodically (once a second is plenty), or have the event
+/// stream do it with `set_rescan_interval`, to notice devices coming and
+/// going.  A controller whose device disappears is kept around in the
+/// disconnected state, and when a device with the same identity shows up
+/// again it gets reattached to it.  Controllers report both transitions as
+/// `ControllerEvent::Connected` and `ControllerEvent::Disconnected` in their
+/// event streams.
+///
+/// Devices turned away by the options' filters are asked who they are again
+/// on every scan, since that's the only way to notice a different device
+/// taking their place.
+///
+/// The manager is also a `Stream` of the events of all its controllers,
+/// including ones attached later, so it takes each controller's event
+/// receiver for itself.  With `set_rescan_interval`, polling the stream also
+/// takes care of scanning.
+pub struct FireManager {
+    transport: Arc<dyn MidiTransport + Send + Sync>,
+    options: DiscoveryOptions,
+    controllers: Vec<FireController>,
+    /// For each controller, its position among the ports sharing its port
+    /// name when it was last connected.
+    slots: Vec<Option<usize>>,
+    /// The number of ports with each name as of the last scan.
+    port_counts: HashMap<String, usize>,
+    /// Enumeration index for the next controller whose device doesn't
+    /// identify itself.
+    next_index: u32,
+    streams: StreamMap<DeviceId, EventReceiver>,
+    rescan: Option<Interval>,
+    /// The identity probes of the scan started by polling the stream, running
+    /// on tokio's blocking threads.
+    probing: Option<JoinHandle<Vec<Probe>>>,
+    /// Errors from scans done while polling the stream.
+    scan_errors: Vec<FireError>,
+}
+
+/// The events of every controller of a `FireManager` with their envelopes,
+/// see `FireManager::envelopes`.
+pub struct Envelopes<'a> {
+    manager: &'a mut FireManager,
+}
+
+/// What the device on a port no connected controller holds said when asked
+/// who it is.
+struct Probe {
+    ports: FirePorts,
+    /// The port's position among the ports sharing its name.
+    position: usize,
+    identity: Result<Option<DeviceIdentity>>,
+}
+
+impl FireManager {
+    /// Creates a manager using the midir transport and default options.
+    /// Nothing is attached until the first `scan`.
+    pub fn new() -> Self {
+        FireManager::with_options(DiscoveryOptions::default())
+    }
+
+    /// Creates a manager using the midir transport and the given options.
+    pub fn with_options(options: DiscoveryOptions) -> Self {
+        FireManager::with_transport(Box::new(options.midir_transport()), options)
+    }
+
+    pub fn with_transport(transport: Box<dyn MidiTransport + Send + Sync>,
+                          options: DiscoveryOptions) -> Self {
+        FireManager {
+            transport: transport.into(),
+            options,
+            controllers: vec![],
+            slots: vec![],
+            port_counts: HashMap::new(),
+            next_index: 0,
+            streams: StreamMap::new(),
+            rescan: None,
+            probing: None,
+            scan_errors: vec![],
+        }
+    }
+
+    /// Sets how often polling the event stream scans for devices coming and
+    /// going, or turns that off.  New devices are asked who they are on
+    /// tokio's blocking threads, so the stream keeps delivering events while
+    /// they answer, and the stream must be polled from within a tokio
+    /// runtime.
+    pub fn set_rescan_interval(&mut self, period: Option<Duration>) {
+        self.rescan = period.map(tokio::time::interval);
+    }
+
+    /// Takes the errors from the scans done while polling the event stream.
+    pub fn take_scan_errors(&mut self) -> Vec<FireError> {
+        std::mem::take(&mut self.scan_errors)
+    }
+
+    /// The events of every controller with their envelopes, for when the
+    /// timestamps matter.
+    pub fn envelopes(&mut self) -> Envelopes<'_> {
+        Envelopes { manager: self }
+    }
+
+    /// Blanks and disconnects every controller, see
+    /// `FireController::shutdown`.  Returns the errors from the ones that
+    /// didn't shut down cleanly.
+    pub fn shutdown(&mut self) -> Vec<(DeviceId, FireError)> {
+        self.controllers.iter_mut().filter_map(|c| {
+            c.shutdown().err().map(|e| (c.id().clone(), e))
+        }).collect()
+    }
+
+    fn poll_envelope(&mut self, cx: &mut Context<'_>) -> Poll<Option<EventEnvelope>> {
+        let mut due = false;
+        if let Some(rescan) = &mut self.rescan {
+            while rescan.poll_tick(cx).is_ready() {
+                due = true;
+            }
+        }
+        // A scan still probing when the next one is due has that one skipped.
+        if due && self.probing.is_none() {
+            match self.unclaimed_ports() {
+                Ok(unclaimed) if unclaimed.is_empty() => (),
+                Ok(unclaimed) => {
+                    let transport = self.transport.clone();
+                    self.probing = Some(tokio::task::spawn_blocking(move || {
+                        probe_all(&*transport, unclaimed)
+                    }));
+                },
+                Err(e) => self.scan_errors.push(e),
+            }
+        }
+        if let Some(probing) = &mut self.probing {
+            if let Poll::Ready(probes) = Pin::new(probing).poll(cx) {
+                self.probing = None;
+                // The probes only fail to finish if one panicked.
+                let probes = probes.map_err(|e| {
+                    FireError::PortEnumeration(format!("probing for devices failed: {}", e))
+                });
+                match probes.and_then(|probes| self.attach_probed(probes)) {
+                    Ok(mut errors) => self.scan_errors.append(&mut errors),
+                    Err(e) => self.scan_errors.push(e),
+                }
+            }
+        }
+
+        // More devices may show up later, so an empty map isn't the end.
+        if self.streams.is_empty() {
+            return Poll::Pending;
+        }
+        match Pin::new(&mut self.streams).poll_next(cx) {
+            Poll::Ready(Some((_, envelope))) => Poll::Ready(Some(envelope)),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Looks for devices that appeared or disappeared since the last scan.
+    /// This blocks while new devices answer the identity request, which takes
+    /// up to half a second for devices that don't.
+    ///
+    /// The outer `Result` fails if the ports couldn't be enumerated.  The inner
+    /// list holds the errors from devices that couldn't be attached; they'll
+    /// be tried again on the next scan.
+    pub fn scan(&mut self) -> Result<Vec<FireError>> {
+        let unclaimed = self.unclaimed_ports()?;
+        let probes = probe_all(&*self.transport, unclaimed);
+        self.attach_probed(probes)
+    }
+
+    /// Disconnects the controllers whose devices may have gone, and returns
+    /// the ports no connected controller holds along with their positions
+    /// among the ports sharing their name.
+    fn unclaimed_ports(&mut self) -> Result<Vec<(FirePorts, usize)>> {
+        let ports = find_fire_ports(&*self.transport, &self.options)?;
+
+        // Port indices shift as devices come and go, so controllers are
+        // matched to ports by name, and among ports sharing a name by
+        // position, which the OS keeps in the same order.  When the number of
+        // ports with a name changed since the last scan, we can't tell which
+        // device came or went, so all of them get disconnected and matched up
+        // again by identity.
+        let (positioned, counts) = positions(&ports);
+        for (c, slot) in self.controllers.iter_mut().zip(&mut self.slots) {
+            let changed = match c.ports() {
+                Some(p) => counts.get(&p.name) != self.port_counts.get(&p.name),
+                None => false,
+            };
+            if changed {
+                c.disconnect();
+            }
+            if !c.is_connected() {
+                *slot = None;
+            }
+        }
+        self.port_counts = counts;
+
+        Ok(positioned.into_iter()
+            .filter(|(p, position)| !self.is_claimed(&p.name, *position))
+            .map(|(p, position)| (p.clone(), position))
+            .collect())
+    }
+
+    /// Whether a connected controller holds the port at `position` among the
+    /// ports named `name`.
+    fn is_claimed(&self, name: &str, position: usize) -> bool {
+        self.controllers.iter().zip(&self.slots).any(|(c, slot)| {
+            *slot == Some(position) && c.ports().is_some_and(|p| p.name == name)
+        })
+    }
+
+    /// Attaches the probed devices the options accept.
+    fn attach_probed(&mut self, probes: Vec<Probe>) -> Result<Vec<FireError>> {
+        // Devices may have come and gone while probing, shifting the port
+        // indices, so the probed ports are looked up again.  Ports whose name
+        // now has a different number of ports are left for the next scan.
+        let ports = find_fire_ports(&*self.transport, &self.options)?;
+        let (positioned, counts) = positions(&ports);
+        let mut errors = vec![];
+        for probe in probes {
+            let name = &probe.ports.name;
+            if counts.get(name) != self.port_counts.get(name)
+                || self.is_claimed(name, probe.position) {
+                continue;
+            }
+            let ports = match positioned.iter()
+                .find(|(p, position)| p.name == *name && *position == probe.position) {
+                Some((ports, _)) => *ports,
+                None => continue,
+            };
+            match probe.identity.and_then(|identity| self.attach_port(ports, identity)) {
+                Ok(Some(i)) => self.slots[i] = Some(probe.position),
+                Ok(None) => (),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        for c in &mut self.controllers {
+            if let Some(rx) = c.take_event_rx() {
+                self.streams.insert(c.id().clone(), rx);
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Reattaches the disconnected controller for the device on `ports` or
+    /// creates a new one if this is a device we haven't seen before.  Returns
+    /// the controller's position, or None if the options turned the device
+    /// away.
+    fn attach_port(&mut self, ports: &FirePorts, identity: Option<DeviceIdentity>)
+        -> Result<Option<usize>> {
+        if !self.options.accepts(&ports.name, identity.as_ref()) {
+            return Ok(None);
+        }
+        let returning = self.controllers.iter().position(|c| {
+            !c.is_connected() && match (&identity, c.identity()) {
+                (Some(probed), Some(known)) => probed == known,
+                // Without identities the best we can do is the port name.
+                (None, None) => match c.id() {
+                    DeviceId::Enumerated(_) => true,
+                    DeviceId::Serial(_) => false,
+                },
+                _ => false,
+            }
+        });
+        match returning {
+            Some(i) => {
+                self.controllers[i].connect_probed(&*self.transport, ports, identity)?;
+                Ok(Some(i))
+            },
+            None => {
+                let c = FireController::attach_probed(
+                    &*self.transport, &self.options, self.next_index, ports, identity)?;
+                self.next_index += 1;
+                self.controllers.push(c);
+                self.slots.push(None);
+                Ok(Some(self.controllers.len() - 1))
+            },
+        }
+    }
+
+    pub fn controllers(&self) -> impl Iterator<Item = &FireController> {
+        self.controllers.iter()
+    }
+
+    pub fn controllers_mut(&mut self) -> impl Iterator<Item = &mut FireController> {
+        self.controllers.iter_mut()
+    }
+
+    pub fn get(&self, id: &DeviceId) -> Option<&FireController> {
+        self.controllers.iter().find(|c| c.id() == id)
+    }
+
+    pub fn get_mut(&mut self, id: &DeviceId) -> Option<&mut FireController> {
+        self.controllers.iter_mut().find(|c| c.id() == id)
+    }
+}
+
+impl Default for FireManager {
+    fn default() -> Self {
+        FireManager::new()
+    }
+}
+
+/// Pairs each of `ports` with its position among the ports sharing its name,
+/// and counts the ports with each name.
+fn positions(ports: &[FirePorts]) -> (Vec<(&FirePorts, usize)>, HashMap<String, usize>) {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let positioned = ports.iter().map(|p| {
+        let count = counts.entry(p.name.clone()).or_insert(0);
+        *count += 1;
+        (p, *count - 1)
+    }).collect();
+    (positioned, counts)
+}
+
+fn probe_all(transport: &dyn MidiTransport, unclaimed: Vec<(FirePorts, usize)>) -> Vec<Probe> {
+    unclaimed.into_iter().map(|(ports, position)| {
+        let identity = probe_identity(transport, &ports);
+        Probe { ports, position, identity }
+    }).collect()
+}
+
+impl Stream for FireManager {
+    type Item = (DeviceId, ControllerEvent);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_envelope(cx).map(|e| e.map(|e| (e.device, e.event)))
+    }
+}
+
+impl<'a> Stream for Envelopes<'a> {
+    type Item = EventEnvelope;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().manager.poll_envelope(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::stream::StreamExt;
+
+    use crate::command::IDENTITY_REQUEST;
+    use crate::discovery::DeviceFilter;
+    use crate::mock::MockTransport;
+    use crate::transport::{InputCallback, InputConnection, OutputConnection};
+
+    fn add_fire(transport: &MockTransport, serial: u8) -> (usize, usize) {
+        transport.add_fire("FL STUDIO FIRE", &[serial])
+    }
+
+    #[test]
+    fn turned_away_device_sharing_a_name_leaves_the_others_alone() {
+        let transport = MockTransport::new();
+        let (_, kept) = add_fire(&transport, 0x0a);
+        let (_, excluded) = add_fire(&transport, 0x0b);
+        let options = DiscoveryOptions::default().exclude(DeviceFilter::Serial("0b".into()));
+        let mut manager = FireManager::with_transport(Box::new(transport.clone()), options);
+
+        assert!(manager.scan().unwrap().is_empty());
+        assert_eq!(manager.controllers().count(), 1);
+        transport.take_sent(kept);
+        for _ in 0..3 {
+            assert!(manager.scan().unwrap().is_empty());
+            // No reconnect, and with it no resync or identity probe.
+            assert!(transport.take_sent(kept).is_empty());
+            assert!(manager.get(&DeviceId::Serial("0a".into())).unwrap().is_connected());
+        }
+        // The turned away device keeps being asked who it is.
+        assert!(transport.take_sent(excluded).contains(&IDENTITY_REQUEST.to_vec()));
+    }
+
+    #[test]
+    fn device_sharing_a_name_coming_back_reattaches() {
+        let transport = MockTransport::new();
+        add_fire(&transport, 0x0a);
+        let (input, output) = add_fire(&transport, 0x0b);
+        let mut manager = FireManager::with_transport(
+            Box::new(transport.clone()), DiscoveryOptions::default());
+        manager.scan().unwrap();
+        let b = DeviceId::Serial("0b".into());
+
+        transport.remove_device(input, output);
+        manager.scan().unwrap();
+        assert!(!manager.get(&b).unwrap().is_connected());
+
+        add_fire(&transport, 0x0b);
+        manager.scan().unwrap();
+        assert_eq!(manager.controllers().count(), 2);
+        assert!(manager.controllers().all(|c| c.is_connected()));
+    }
+
+    #[tokio::test]
+    async fn polling_attaches_new_devices() {
+        let transport = MockTransport::new();
+        add_fire(&transport, 0x0a);
+        let mut manager = FireManager::with_transport(
+            Box::new(transport.clone()), DiscoveryOptions::default());
+        manager.set_rescan_interval(Some(Duration::from_millis(10)));
+        assert_eq!(manager.next().await,
+                   Some((DeviceId::Serial("0a".into()), ControllerEvent::Connected)));
+    }
+
+    #[tokio::test]
+    async fn probing_doesnt_hold_up_the_stream() {
+        let transport = MockTransport::new();
+        // Never answers the identity request.
+        transport.add_device("FL STUDIO FIRE");
+        let mut manager = FireManager::with_transport(
+            Box::new(transport.clone()), DiscoveryOptions::default());
+        manager.set_rescan_interval(Some(Duration::from_millis(10)));
+
+        let start = Instant::now();
+        let next = tokio::time::timeout(Duration::from_millis(50), manager.next()).await;
+        assert!(next.is_err());
+        assert!(start.elapsed() < Duration::from_millis(400));
+        // Once the probe gives up, the device gets attached without being
+        // asked again.
+        assert_eq!(manager.next().await,
+                   Some((DeviceId::Enumerated(0), ControllerEvent::Connected)));
+        assert!(start.elapsed() < Duration::from_millis(900));
+    }
+
+    /// Lists a Fire but panics when connecting to it.
+    struct PanickingTransport;
+
+    impl MidiTransport for PanickingTransport {
+        fn input_port_names(&self) -> Result<Vec<String>> {
+            Ok(vec!["FL STUDIO FIRE".into()])
+        }
+
+        fn output_port_names(&self) -> Result<Vec<String>> {
+            Ok(vec!["FL STUDIO FIRE".into()])
+        }
+
+        fn connect_input(&self, _port: usize, _callback: InputCallback)
+            -> Result<Box<dyn InputConnection>> {
+            panic!("connecting")
+        }
+
+        fn connect_output(&self, _port: usize)
+            -> Result<Box<dyn OutputConnection>> {
+            panic!("connecting")
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_probe_is_a_scan_error() {
+        let mut manager = FireManager::with_transport(
+            Box::new(PanickingTransport), DiscoveryOptions::default());
+        manager.set_rescan_interval(Some(Duration::from_millis(10)));
+        let next = tokio::time::timeout(Duration::from_millis(200), manager.next()).await;
+        assert!(next.is_err());
+        let errors = manager.take_scan_errors();
+        assert!(matches!(errors.first(), Some(FireError::PortEnumeration(_))), "{:?}", errors);
+    }
+}
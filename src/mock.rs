@@ -0,0 +1,323 @@
+//! In-memory `MidiTransport` for exercising controllers without hardware.
+//!
+//! Tests add ports, attach controllers to them as usual, then feed input bytes
+//! with `feed_input` and inspect what the controller sent with `take_sent`.
+//!
+//! Ports are referred to by the slot returned when adding them.  Slots stay
+//! the same when other ports are removed, unlike the port indices seen through
+//! `MidiTransport`, which shift just like real ones do.
+
+use std::sync::{Arc, Mutex};
+
+use crate::command::IDENTITY_REQUEST;
+use crate::error::{FireError, Result};
+use crate::transport::{
+    missing_port, InputCallback, InputConnection, MidiTransport, OutputConnection,
+};
+
+/// Cheaply cloneable handle; all clones share the same ports.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    inner: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    inputs: Vec<MockInputPort>,
+    outputs: Vec<MockOutputPort>,
+    responders: Vec<Responder>,
+}
+
+struct MockInputPort {
+    name: String,
+    present: bool,
+    callback: Option<InputCallback>,
+}
+
+struct MockOutputPort {
+    name: String,
+    present: bool,
+    connected: bool,
+    sent: Vec<Vec<u8>>,
+}
+
+/// Canned reply played back whenever a given message is sent, to stand in for
+/// things like the device answering identity requests.
+struct Responder {
+    output: usize,
+    request: Vec<u8>,
+    input: usize,
+    reply: Vec<u8>,
+}
+
+impl MockState {
+    /// Maps a `MidiTransport` input port index to its slot.
+    fn input_slot(&self, port: usize) -> Option<usize> {
+        self.inputs.iter().enumerate().filter(|(_, p)| p.present).nth(port).map(|(slot, _)| slot)
+    }
+
+    /// Maps a `MidiTransport` output port index to its slot.
+    fn output_slot(&self, port: usize) -> Option<usize> {
+        self.outputs.iter().enumerate().filter(|(_, p)| p.present).nth(port).map(|(slot, _)| slot)
+    }
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        MockTransport::default()
+    }
+
+    /// Adds an input port, returning its slot.
+    pub fn add_input_port(&self, name: &str) -> usize {
+        let mut state = self.inner.lock().unwrap();
+        state.inputs.push(MockInputPort {
+            name: name.to_string(),
+            present: true,
+            callback: None,
+        });
+        state.inputs.len() - 1
+    }
+
+    /// Adds an output port, returning its slot.
+    pub fn add_output_port(&self, name: &str) -> usize {
+        let mut state = self.inner.lock().unwrap();
+        state.outputs.push(MockOutputPort {
+            name: name.to_string(),
+            present: true,
+            connected: false,
+            sent: vec![],
+        });
+        state.outputs.len() - 1
+    }
+
+    /// Adds an input and an output port with the same name, like a Fire on
+    /// Windows, returning their slots.
+    pub fn add_device(&self, name: &str) -> (usize, usize) {
+        (self.add_input_port(name), self.add_output_port(name))
+    }
+
+    /// Adds a device like `add_device` that answers the identity request
+    /// like a Fire with the given serial number, which has to be 7-bit.
+    pub fn add_fire(&self, name: &str, serial: &[u8]) -> (usize, usize) {
+        let (input, output) = self.add_device(name);
+        let mut reply = vec![0xf0, 0x7e, 0x00, 0x06, 0x02, 0x47, 0x43, 0x00, 0x19, 0x00,
+                             0x01, 0x00, 0x00, 0x00];
+        reply.extend_from_slice(serial);
+        reply.push(0xf7);
+        self.respond_to(output, &IDENTITY_REQUEST, input, &reply);
+        (input, output)
+    }
+
+    /// Unplugs the device with the given input and output slots.  Its ports
+    /// stop being listed, its input goes quiet and sending to it fails.
+    pub fn remove_device(&self, input: usize, output: usize) {
+        let mut state = self.inner.lock().unwrap();
+        if let Some(p) = state.inputs.get_mut(input) {
+            p.present = false;
+            p.callback = None;
+        }
+        if let Some(p) = state.outputs.get_mut(output) {
+            p.present = false;
+        }
+    }
+
+    /// Whenever `request` is sent to output slot `output`, feed `reply` into
+    /// input slot `input`.
+    pub fn respond_to(&self, output: usize, request: &[u8], input: usize, reply: &[u8]) {
+        let mut state = self.inner.lock().unwrap();
+        state.responders.push(Responder {
+            output,
+            request: request.to_vec(),
+            input,
+            reply: reply.to_vec(),
+        });
+    }
+
+    /// Delivers `bytes` to whoever is connected to input slot `slot` as if the
+    /// device had sent them.  Returns false if nobody is connected.
+    pub fn feed_input(&self, slot: usize, stamp: u64, bytes: &[u8]) -> bool {
+        // Don't hold the lock while running the callback in case it wants to
+        // talk back to us.
+        let callback = {
+            let mut state = self.inner.lock().unwrap();
+            match state.inputs.get_mut(slot) {
+                Some(p) => p.callback.take(),
+                None => None,
+            }
+        };
+        match callback {
+            Some(mut callback) => {
+                callback(stamp, bytes);
+                let mut state = self.inner.lock().unwrap();
+                if let Some(p) = state.inputs.get_mut(slot) {
+                    if p.present && p.callback.is_none() {
+                        p.callback = Some(callback);
+                    }
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Removes and returns every message sent to output slot `slot` so far.
+    pub fn take_sent(&self, slot: usize) -> Vec<Vec<u8>> {
+        let mut state = self.inner.lock().unwrap();
+        match state.outputs.get_mut(slot) {
+            Some(p) => std::mem::take(&mut p.sent),
+            None => vec![],
+        }
+    }
+
+    /// Whether something currently holds a connection to input slot `slot`.
+    pub fn is_input_connected(&self, slot: usize) -> bool {
+        let state = self.inner.lock().unwrap();
+        state.inputs.get(slot).is_some_and(|p| p.callback.is_some())
+    }
+
+    /// Whether something currently holds a connection to output slot `slot`.
+    pub fn is_output_connected(&self, slot: usize) -> bool {
+        let state = self.inner.lock().unwrap();
+        state.outputs.get(slot).is_some_and(|p| p.connected)
+    }
+}
+
+impl MidiTransport for MockTransport {
+    fn input_port_names(&self) -> Result<Vec<String>> {
+        let state = self.inner.lock().unwrap();
+        Ok(state.inputs.iter().filter(|p| p.present).map(|p| p.name.clone()).collect())
+    }
+
+    fn output_port_names(&self) -> Result<Vec<String>> {
+        let state = self.inner.lock().unwrap();
+        Ok(state.outputs.iter().filter(|p| p.present).map(|p| p.name.clone()).collect())
+    }
+
+    fn connect_input(&self, port: usize, callback: InputCallback)
+        -> Result<Box<dyn InputConnection>> {
+        let mut state = self.inner.lock().unwrap();
+        let slot = state.input_slot(port).ok_or_else(|| missing_port(port))?;
+        let p = &mut state.inputs[slot];
+        if p.callback.is_some() {
+            return Err(FireError::Connect {
+                port: p.name.clone(),
+                reason: "port is busy".to_string(),
+            });
+        }
+        p.callback = Some(callback);
+        Ok(Box::new(MockInputConnection {
+            inner: self.inner.clone(),
+            slot,
+        }))
+    }
+
+    fn connect_output(&self, port: usize) -> Result<Box<dyn OutputConnection>> {
+        let mut state = self.inner.lock().unwrap();
+        let slot = state.output_slot(port).ok_or_else(|| missing_port(port))?;
+        state.outputs[slot].connected = true;
+        Ok(Box::new(MockOutputConnection {
+            transport: self.clone(),
+            slot,
+        }))
+    }
+}
+
+struct MockInputConnection {
+    inner: Arc<Mutex<MockState>>,
+    slot: usize,
+}
+
+impl InputConnection for MockInputConnection {}
+
+impl Drop for MockInputConnection {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.inner.lock() {
+            if let Some(p) = state.inputs.get_mut(self.slot) {
+                p.callback = None;
+            }
+        }
+    }
+}
+
+struct MockOutputConnection {
+    transport: MockTransport,
+    slot: usize,
+}
+
+impl OutputConnection for MockOutputConnection {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        let replies: Vec<(usize, Vec<u8>)> = {
+            let mut state = self.transport.inner.lock().unwrap();
+            let p = &mut state.outputs[self.slot];
+            if !p.present {
+                return Err(FireError::Send("device was unplugged".to_string()));
+            }
+            p.sent.push(msg.to_vec());
+            state.responders.iter()
+                .filter(|r| r.output == self.slot && r.request == msg)
+                .map(|r| (r.input, r.reply.clone()))
+                .collect()
+        };
+        for (input, reply) in replies {
+            self.transport.feed_input(input, 0, &reply);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MockOutputConnection {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.transport.inner.lock() {
+            if let Some(p) = state.outputs.get_mut(self.slot) {
+                p.connected = false;
+            }
+        }
+    }
+}
+
+/// A controller attached to a mock Fire, for the tests of the modules it's
+/// built from.
+#[cfg(test)]
+pub(crate) struct TestFire {
+    pub transport: MockTransport,
+    pub controller: crate::controller::FireController,
+    pub events: crate::queue::EventReceiver,
+    pub input: usize,
+    pub output: usize,
+}
+
+#[cfg(test)]
+impl TestFire {
+    /// Attaches to a new mock Fire with the given options.  Whatever
+    /// attaching sent and emitted is already taken.
+    pub fn attach(options: &crate::discovery::DiscoveryOptions) -> Self {
+        let transport = MockTransport::new();
+        let (input, output) = transport.add_fire("FL STUDIO FIRE", &[0x12, 0x34]);
+        let mut controller = crate::controller::FireController::attach_to_all_with(
+            &transport, options).unwrap().remove(0).unwrap();
+        let events = controller.take_event_rx().unwrap();
+        // The identity reply is fed from the writer thread, and the mock
+        // doesn't let feeds overlap.
+        controller.output().flush().unwrap();
+        let mut fire = TestFire { transport, controller, events, input, output };
+        fire.sent();
+        fire.events();
+        fire
+    }
+
+    /// Feeds bytes from the device.
+    pub fn feed(&self, bytes: &[u8]) {
+        assert!(self.transport.feed_input(self.input, 0, bytes));
+    }
+
+    /// Takes the queued events.
+    pub fn events(&mut self) -> Vec<crate::input::ControllerEvent> {
+        std::iter::from_fn(|| self.events.try_recv()).map(|envelope| envelope.event).collect()
+    }
+
+    /// Takes what was sent to the device, once everything queued is out.
+    pub fn sent(&self) -> Vec<Vec<u8>> {
+        self.controller.output().flush().unwrap();
+        self.transport.take_sent(self.output)
+    }
+}
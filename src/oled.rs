@@ -0,0 +1,126 @@
+//! The 128x64 monochrome OLED.
+//!
+//! The device takes pixels as 8 pixel tall "bands" of columns, with every 7
+//! columns' worth of bits scrambled into 8 7-bit sysex bytes.  The scrambling
+//! table is straight out of the Segger write-up.
+
+use crate::command::ControllerCommand;
+use crate::error::{FireError, Result};
+
+pub const OLED_WIDTH: usize = 128;
+pub const OLED_HEIGHT: usize = 64;
+/// Number of 8 pixel tall bands.
+pub const OLED_BANDS: usize = OLED_HEIGHT / 8;
+
+/// For pixel row `y % 8` and column `x % 7` within a group of 7 columns,
+/// which of the group's 56 bits holds the pixel.
+const BIT_MUTATE: [[u8; 7]; 8] = [
+    [13, 0, 1, 2, 3, 4, 5],
+    [19, 20, 7, 8, 9, 10, 11],
+    [25, 26, 27, 14, 15, 16, 17],
+    [31, 32, 33, 34, 21, 22, 23],
+    [37, 38, 39, 40, 41, 28, 29],
+    [43, 44, 45, 46, 47, 48, 35],
+    [49, 50, 51, 52, 53, 54, 55],
+    [6, 12, 18, 24, 30, 36, 42],
+];
+
+/// Number of packed bytes needed for `columns` 8 pixel tall columns.
+fn packed_len(columns: usize) -> usize {
+    columns.div_ceil(7) * 8
+}
+
+/// Where the pixel at row `y` of the `k`th column of a write lives in the
+/// packed data: (byte, bit).
+fn packed_position(k: usize, y: usize) -> (usize, u8) {
+    let remap = BIT_MUTATE[y % 8][k % 7] as usize;
+    (k / 7 * 8 + remap / 7, (remap % 7) as u8)
+}
+
+/// One bit per pixel, row-major.
+#[derive(Clone, PartialEq, Eq)]
+pub struct OledBitmap {
+    pixels: [[bool; OLED_WIDTH]; OLED_HEIGHT],
+}
+
+impl OledBitmap {
+    pub fn new() -> Self {
+        OledBitmap {
+            pixels: [[false; OLED_WIDTH]; OLED_HEIGHT],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels = [[false; OLED_WIDTH]; OLED_HEIGHT];
+    }
+
+    /// Sets a pixel, failing if it's off the screen.
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) -> Result<()> {
+        if x >= OLED_WIDTH || y >= OLED_HEIGHT {
+            return Err(FireError::InvalidArgument(
+                format!("pixel ({}, {}) is off the {}x{} screen", x, y, OLED_WIDTH, OLED_HEIGHT)));
+        }
+        self.pixels[y][x] = on;
+        Ok(())
+    }
+
+    /// Whether a pixel is lit.  Pixels off the screen are never lit.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        x < OLED_WIDTH && y < OLED_HEIGHT && self.pixels[y][x]
+    }
+
+    /// The command that draws the whole bitmap.
+    pub fn to_command(&self) -> ControllerCommand {
+        let mut data = vec![0u8; packed_len(OLED_WIDTH * OLED_BANDS)];
+        for band in 0..OLED_BANDS {
+            for x in 0..OLED_WIDTH {
+                let k = band * OLED_WIDTH + x;
+                for y in 0..8 {
+                    if self.pixels[band * 8 + y][x] {
+                        let (byte, bit) = packed_position(k, y);
+                        data[byte] |= 1 << bit;
+                    }
+                }
+            }
+        }
+        ControllerCommand::OledWrite {
+            start_band: 0,
+            end_band: (OLED_BANDS - 1) as u8,
+            start_column: 0,
+            end_column: (OLED_WIDTH - 1) as u8,
+            data,
+        }
+    }
+
+    /// Updates the bitmap with what an `OledWrite` command would draw.  Other
+    /// commands and out of range writes are ignored.
+    pub fn apply(&mut self, command: &ControllerCommand) {
+        let (start_band, end_band, start_column, end_column, data) = match command {
+            ControllerCommand::OledWrite { start_band, end_band, start_column, end_column, data } =>
+                (*start_band as usize, *end_band as usize,
+                 *start_column as usize, *end_column as usize, data),
+            _ => return,
+        };
+        if start_band > end_band || end_band >= OLED_BANDS ||
+           start_column > end_column || end_column >= OLED_WIDTH {
+            return;
+        }
+        let width = end_column - start_column + 1;
+        for band in start_band..=end_band {
+            for x in start_column..=end_column {
+                let k = (band - start_band) * width + (x - start_column);
+                for y in 0..8 {
+                    let (byte, bit) = packed_position(k, y);
+                    let on = data.get(byte).is_some_and(|b| b & (1 << bit) != 0);
+                    self.pixels[band * 8 + y][x] = on;
+                }
+            }
+        }
+    }
+}
+
+impl Default for OledBitmap {
+    fn default() -> Self {
+        OledBitmap::new()
+    }
+}
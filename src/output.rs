@@ -0,0 +1,344 @@
+use std::cmp::min;
+
+use crate::color::Color;
+use crate::command::{ControllerCommand, PadColor};
+use crate::error::{FireError, Result};
+use crate::input::{ChannelMode, ControllerButton};
+
+/// Number of RGB pads in the grid.
+pub const PAD_COUNT: usize = 64;
+
+/// The colors we want the 64 grid pads to show.
+pub struct PadLedBuffer {
+    colors: [[u8; 3]; PAD_COUNT],
+}
+
+impl PadLedBuffer {
+    pub fn new() -> Self {
+        PadLedBuffer {
+            colors: [[0; 3]; PAD_COUNT],
+        }
+    }
+
+    /// Do a basic 4x4 color cube cut into 4 slices.
+    pub fn set_color_cube(&mut self) {
+        for i in 0..PAD_COUNT as u8 {
+            let x: u8 = i % 4;
+            let y: u8 = i / 16;
+            let z: u8 = (i % 16) / 4;
+            self.write_led(i as usize, x * 0x20, y * 0x20, z * 0x20);
+        }
+    }
+
+    /// Sets the color of grid pad `i`, failing if `i` isn't a valid pad.  The
+    /// components are raw 7-bit values and get clamped to 0x7f; use
+    /// `set_color` for colors picked on a screen.
+    pub fn set_led(&mut self, i: u8, r: u8, g: u8, b: u8) -> Result<()> {
+        if i as usize >= PAD_COUNT {
+            return Err(FireError::InvalidArgument(
+                format!("pad index {} is not less than {}", i, PAD_COUNT)));
+        }
+        self.write_led(i as usize, r, g, b);
+        Ok(())
+    }
+
+    /// Sets pad `i` to look like an 8-bit screen color, see `Color::to_fire`.
+    pub fn set_color(&mut self, i: u8, color: Color) -> Result<()> {
+        let [r, g, b] = color.to_fire();
+        self.set_led(i, r, g, b)
+    }
+
+    fn write_led(&mut self, i: usize, r: u8, g: u8, b: u8) {
+        self.colors[i] = [min(0x7f, r), min(0x7f, g), min(0x7f, b)];
+    }
+
+    /// The command that sets every pad to its color.
+    pub fn to_command(&self) -> ControllerCommand {
+        ControllerCommand::PadColors(self.colors.iter().enumerate().map(|(i, &[r, g, b])| {
+            PadColor { index: i as u8, r, g, b }
+        }).collect())
+    }
+}
+
+impl Default for PadLedBuffer {
+    fn default() -> Self {
+        PadLedBuffer::new()
+    }
+}
+
+/// What a button's LED can show.  Each button only supports some of these,
+/// see `ControllerButton::led_states`.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ButtonLedState {
+    Off,
+    DullRed,
+    DullGreen,
+    DullYellow,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+}
+
+// The LEDs take the position of the state in the button's palette as the CC
+// value.
+const RED: &[ButtonLedState] = &[
+    ButtonLedState::Off, ButtonLedState::DullRed, ButtonLedState::BrightRed,
+];
+const GREEN: &[ButtonLedState] = &[
+    ButtonLedState::Off, ButtonLedState::DullGreen, ButtonLedState::BrightGreen,
+];
+const YELLOW: &[ButtonLedState] = &[
+    ButtonLedState::Off, ButtonLedState::DullYellow, ButtonLedState::BrightYellow,
+];
+const RED_YELLOW: &[ButtonLedState] = &[
+    ButtonLedState::Off, ButtonLedState::DullRed, ButtonLedState::DullYellow,
+    ButtonLedState::BrightRed, ButtonLedState::BrightYellow,
+];
+const GREEN_YELLOW: &[ButtonLedState] = &[
+    ButtonLedState::Off, ButtonLedState::DullGreen, ButtonLedState::DullYellow,
+    ButtonLedState::BrightGreen, ButtonLedState::BrightYellow,
+];
+const RED_GREEN: &[ButtonLedState] = &[
+    ButtonLedState::Off, ButtonLedState::DullRed, ButtonLedState::DullGreen,
+    ButtonLedState::BrightRed, ButtonLedState::BrightGreen,
+];
+
+/// Number of track rows, each with a button and an LED next to the grid.
+pub const TRACK_ROWS: u8 = 4;
+/// CC number of the LED next to the first track row; the others follow.
+pub(crate) const TRACK_LED_CC: u8 = 0x28;
+/// CC number of the Channel/Mixer/User 1/User 2 indicator LEDs.
+pub(crate) const MODE_LED_CC: u8 = 0x1b;
+/// Value turning all the mode indicator LEDs off.  0..=3 light the one for
+/// the corresponding mode.
+const MODE_LED_OFF: u8 = 0x10;
+
+impl ControllerButton {
+    /// The states the button's LED can show.  Empty for the buttons without
+    /// an LED of their own: Channel, whose mode is shown by separate LEDs,
+    /// and the Select knob.
+    pub fn led_states(self) -> &'static [ButtonLedState] {
+        match self {
+            ControllerButton::Channel | ControllerButton::SelectPress => &[],
+            ControllerButton::PatternUp | ControllerButton::PatternDown |
+            ControllerButton::Browser | ControllerButton::GridLeft |
+            ControllerButton::GridRight => RED,
+            ControllerButton::Row1 | ControllerButton::Row2 | ControllerButton::Row3 |
+            ControllerButton::Row4 => GREEN,
+            ControllerButton::Step | ControllerButton::Note | ControllerButton::Drum |
+            ControllerButton::Perform | ControllerButton::Shift | ControllerButton::Alt |
+            ControllerButton::Pattern | ControllerButton::Record => RED_YELLOW,
+            ControllerButton::Play => GREEN_YELLOW,
+            ControllerButton::Stop => YELLOW,
+        }
+    }
+
+    /// The command showing `state` on the button's LED.
+    pub fn led_command(self, state: ButtonLedState) -> Result<ControllerCommand> {
+        match self.led_states().iter().position(|&s| s == state) {
+            Some(value) => Ok(ControllerCommand::ButtonLed { cc: self.note(), value: value as u8 }),
+            None => Err(FireError::InvalidArgument(
+                format!("{:?} can't show {:?}", self, state))),
+        }
+    }
+
+    /// The state a CC value sent to the button's LED shows.
+    pub fn led_state(self, value: u8) -> Option<ButtonLedState> {
+        self.led_states().get(value as usize).copied()
+    }
+}
+
+/// The states the LEDs next to the track rows can show.
+pub fn track_led_states() -> &'static [ButtonLedState] {
+    RED_GREEN
+}
+
+/// The command showing `state` on the LED next to track row `row` (0-based,
+/// top to bottom).
+pub fn track_led_command(row: u8, state: ButtonLedState) -> Result<ControllerCommand> {
+    if row >= TRACK_ROWS {
+        return Err(FireError::InvalidArgument(
+            format!("track row {} is not less than {}", row, TRACK_ROWS)));
+    }
+    match RED_GREEN.iter().position(|&s| s == state) {
+        Some(value) => Ok(ControllerCommand::ButtonLed { cc: TRACK_LED_CC + row, value: value as u8 }),
+        None => Err(FireError::InvalidArgument(
+            format!("track row LEDs can't show {:?}", state))),
+    }
+}
+
+/// The state a CC value sent to a track row LED shows.
+pub fn track_led_state(value: u8) -> Option<ButtonLedState> {
+    RED_GREEN.get(value as usize).copied()
+}
+
+/// The command lighting the indicator LED for `mode`, or turning them all off
+/// for None.  The indicators are single-color, and only one can be lit.
+pub fn mode_led_command(mode: Option<ChannelMode>) -> ControllerCommand {
+    let value = match mode {
+        Some(ChannelMode::Channel) => 0,
+        Some(ChannelMode::Mixer) => 1,
+        Some(ChannelMode::User1) => 2,
+        Some(ChannelMode::User2) => 3,
+        None => MODE_LED_OFF,
+    };
+    ControllerCommand::ButtonLed { cc: MODE_LED_CC, value }
+}
+
+/// The value turning off the LED with CC number `cc`.  That's 0 for all of
+/// them except the mode indicators, where 0 lights the Channel one.
+pub(crate) fn cc_led_off(cc: u8) -> u8 {
+    if cc == MODE_LED_CC { MODE_LED_OFF } else { 0 }
+}
+
+/// The CC numbers of every LED addressed by CC: the button LEDs, the track
+/// row LEDs and the mode indicators.
+pub(crate) fn led_ccs() -> impl Iterator<Item = u8> {
+    ControllerButton::all()
+        .filter(|button| !button.led_states().is_empty())
+        .map(ControllerButton::note)
+        .chain(TRACK_LED_CC..TRACK_LED_CC + TRACK_ROWS)
+        .chain(std::iter::once(MODE_LED_CC))
+}
+
+/// The lit mode indicator for a CC value sent to the indicator LEDs.
+pub fn mode_led_state(value: u8) -> Option<ChannelMode> {
+    match value {
+        0 => Some(ChannelMode::Channel),
+        1 => Some(ChannelMode::Mixer),
+        2 => Some(ChannelMode::User1),
+        3 => Some(ChannelMode::User2),
+        _ => None,
+    }
+}
+
+/// Below this brightness the LEDs without dimming of their own go off, and
+/// below `DULL_LEVEL` bright states go dull.
+const OFF_LEVEL: f64 = 0.15;
+const DULL_LEVEL: f64 = 0.5;
+
+impl ButtonLedState {
+    /// The dull version of a bright state.
+    fn dulled(self) -> Self {
+        match self {
+            ButtonLedState::BrightRed => ButtonLedState::DullRed,
+            ButtonLedState::BrightGreen => ButtonLedState::DullGreen,
+            ButtonLedState::BrightYellow => ButtonLedState::DullYellow,
+            state => state,
+        }
+    }
+}
+
+/// The value to send to the LED with CC number `cc` so that it shows `value`
+/// at brightness `level`.  The LEDs only know dull and bright, so dimming
+/// steps bright states down to dull ones and then turns the LED off.  Values
+/// outside the LED's palette are left alone.
+pub(crate) fn dim_cc_led(cc: u8, value: u8, level: f64) -> u8 {
+    if level >= DULL_LEVEL {
+        return value;
+    }
+    if cc == MODE_LED_CC {
+        return if level < OFF_LEVEL { MODE_LED_OFF } else { value };
+    }
+    let palette = if (TRACK_LED_CC..TRACK_LED_CC + TRACK_ROWS).contains(&cc) {
+        RED_GREEN
+    } else {
+        match ControllerButton::from_note(cc) {
+            Some(button) => button.led_states(),
+            None => return value,
+        }
+    };
+    let state = match palette.get(value as usize) {
+        Some(&state) => state,
+        None => return value,
+    };
+    let dimmed = if level < OFF_LEVEL { ButtonLedState::Off } else { state.dulled() };
+    // Every palette with a bright state has its dull version, and all of
+    // them have Off.
+    palette.iter().position(|&s| s == dimmed).map_or(value, |position| position as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::DiscoveryOptions;
+    use crate::mock::TestFire;
+
+    #[test]
+    fn button_leds_send_their_palette_position() {
+        let fire = TestFire::attach(&DiscoveryOptions::default());
+        let output = fire.controller.output();
+        output.set_button_led(ControllerButton::Play, ButtonLedState::BrightGreen).unwrap();
+        output.set_button_led(ControllerButton::Stop, ButtonLedState::DullYellow).unwrap();
+        output.set_button_led(ControllerButton::Record, ButtonLedState::BrightYellow).unwrap();
+        output.set_track_led(2, ButtonLedState::DullGreen).unwrap();
+        output.set_mode_led(Some(ChannelMode::User1)).unwrap();
+        assert_eq!(fire.sent(), vec![
+            vec![0xb0, 0x33, 0x03],
+            vec![0xb0, 0x34, 0x01],
+            vec![0xb0, 0x35, 0x04],
+            vec![0xb0, 0x2a, 0x02],
+            vec![0xb0, 0x1b, 0x02],
+        ]);
+    }
+
+    #[test]
+    fn states_a_led_cant_show_are_rejected() {
+        let fire = TestFire::attach(&DiscoveryOptions::default());
+        let output = fire.controller.output();
+        assert!(matches!(output.set_button_led(ControllerButton::Stop, ButtonLedState::BrightRed),
+                         Err(FireError::InvalidArgument(_))));
+        assert!(matches!(output.set_button_led(ControllerButton::Channel, ButtonLedState::Off),
+                         Err(FireError::InvalidArgument(_))));
+        assert!(matches!(output.set_track_led(0, ButtonLedState::BrightYellow),
+                         Err(FireError::InvalidArgument(_))));
+        assert!(matches!(output.set_track_led(TRACK_ROWS, ButtonLedState::Off),
+                         Err(FireError::InvalidArgument(_))));
+        assert!(fire.sent().is_empty());
+    }
+
+    #[test]
+    fn shadow_looks_up_led_states() {
+        let fire = TestFire::attach(&DiscoveryOptions::default());
+        let output = fire.controller.output();
+        assert_eq!(output.shadow().button_led(ControllerButton::Play), None);
+        output.set_button_led(ControllerButton::Play, ButtonLedState::DullYellow).unwrap();
+        output.set_track_led(3, ButtonLedState::BrightRed).unwrap();
+        output.set_mode_led(Some(ChannelMode::Mixer)).unwrap();
+
+        let shadow = output.shadow();
+        assert_eq!(shadow.button_led(ControllerButton::Play), Some(ButtonLedState::DullYellow));
+        assert_eq!(shadow.button_led(ControllerButton::Channel), None);
+        assert_eq!(shadow.track_led(3), Some(ButtonLedState::BrightRed));
+        assert_eq!(shadow.track_led(0), None);
+        assert_eq!(shadow.track_led(TRACK_ROWS), None);
+        assert_eq!(shadow.mode_led(), Some(ChannelMode::Mixer));
+
+        output.set_mode_led(None).unwrap();
+        assert_eq!(output.shadow().mode_led(), None);
+        assert_eq!(output.shadow().cc_led(MODE_LED_CC), Some(MODE_LED_OFF));
+    }
+
+    #[test]
+    fn dimmed_leds_go_dull_then_off() {
+        let play = ControllerButton::Play.note();
+        let bright_green = play_value(ButtonLedState::BrightGreen);
+        let dull_green = play_value(ButtonLedState::DullGreen);
+        assert_eq!(dim_cc_led(play, bright_green, 1.0), bright_green);
+        assert_eq!(dim_cc_led(play, bright_green, DULL_LEVEL), bright_green);
+        assert_eq!(dim_cc_led(play, bright_green, 0.3), dull_green);
+        assert_eq!(dim_cc_led(play, dull_green, 0.3), dull_green);
+        assert_eq!(dim_cc_led(play, bright_green, 0.1), 0);
+
+        // Track rows use the red and green palette.
+        assert_eq!(dim_cc_led(TRACK_LED_CC + 1, 3, 0.3), 1);
+        assert_eq!(dim_cc_led(TRACK_LED_CC + 1, 3, 0.1), 0);
+        // The mode indicators only have on and off.
+        assert_eq!(dim_cc_led(MODE_LED_CC, 2, 0.3), 2);
+        assert_eq!(dim_cc_led(MODE_LED_CC, 2, 0.1), MODE_LED_OFF);
+    }
+
+    fn play_value(state: ButtonLedState) -> u8 {
+        ControllerButton::Play.led_states().iter().position(|&s| s == state).unwrap() as u8
+    }
+}
@@ -0,0 +1,216 @@
+//! Stateful parsing of the raw MIDI byte stream coming from a controller.
+//!
+//! The MIDI backend doesn't promise to hand us exactly one message per
+//! callback, so `MidiParser` keeps state across calls to `feed` in order to
+//! handle:
+//! - running status, where the status byte of repeated messages is omitted.
+//! - several messages in one buffer.
+//! - realtime bytes (0xf8..=0xff) that may appear anywhere, even mid-message.
+//! - sysex messages split across several buffers.
+
+use crate::input::ControllerEvent;
+
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+
+/// Something found in the byte stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParsedMessage {
+    /// A channel message the controller is known to send.
+    Event(ControllerEvent),
+    /// A complete sysex message, including the 0xf0 and 0xf7 framing bytes.
+    Sysex(Vec<u8>),
+    /// A realtime byte.
+    Realtime(u8),
+    /// Bytes that couldn't be interpreted: data bytes without a status byte,
+    /// a sysex interrupted by another status byte, or a well-formed message
+    /// that doesn't mean anything for the controller.
+    Unparsed(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+pub struct MidiParser {
+    /// The status byte of the channel message being parsed or, in between
+    /// messages, the one to reuse for running status.
+    status: Option<u8>,
+    /// Data bytes of the current message collected so far.
+    data: Vec<u8>,
+    /// The sysex collected so far, if we're in one.
+    sysex: Option<Vec<u8>>,
+    /// Consecutive data bytes that don't belong to anything.
+    stray: Vec<u8>,
+}
+
+/// Number of data bytes following the given status byte, for everything but
+/// sysex and realtime.
+fn data_len(status: u8) -> usize {
+    match status {
+        0x80..=0xbf | 0xe0..=0xef => 2,
+        0xc0..=0xdf => 1,
+        // MIDI time code quarter frame and song select.
+        0xf1 | 0xf3 => 1,
+        // Song position pointer.
+        0xf2 => 2,
+        // Tune request, undefined and end of exclusive.
+        _ => 0,
+    }
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        MidiParser::default()
+    }
+
+    /// Parses `bytes`, calling `emit` for everything found.  Incomplete
+    /// messages are remembered and completed by subsequent calls.
+    pub fn feed<F: FnMut(ParsedMessage)>(&mut self, bytes: &[u8], mut emit: F) {
+        for &b in bytes {
+            match b {
+                // Realtime bytes don't disturb anything else in progress.
+                0xf8..=0xff => emit(ParsedMessage::Realtime(b)),
+                SYSEX_START => {
+                    self.interrupt(&mut emit);
+                    self.sysex = Some(vec![b]);
+                },
+                SYSEX_END if self.sysex.is_some() => {
+                    let mut sysex = self.sysex.take().unwrap();
+                    sysex.push(b);
+                    emit(ParsedMessage::Sysex(sysex));
+                },
+                0x80..=0xf7 => {
+                    self.interrupt(&mut emit);
+                    self.status = Some(b);
+                    self.complete_if_ready(&mut emit);
+                },
+                _ => {
+                    if let Some(sysex) = &mut self.sysex {
+                        sysex.push(b);
+                    } else if self.status.is_some() {
+                        self.data.push(b);
+                        self.complete_if_ready(&mut emit);
+                    } else {
+                        self.stray.push(b);
+                    }
+                },
+            }
+        }
+        // Stray bytes can't become valid later, so don't sit on them.
+        self.flush_stray(&mut emit);
+    }
+
+    /// A non-realtime status byte arrived, abandoning anything incomplete.
+    fn interrupt<F: FnMut(ParsedMessage)>(&mut self, emit: &mut F) {
+        self.flush_stray(emit);
+        if let Some(sysex) = self.sysex.take() {
+            emit(ParsedMessage::Unparsed(sysex));
+        }
+        if let Some(status) = self.status.take() {
+            if !self.data.is_empty() {
+                let mut partial = vec![status];
+                partial.append(&mut self.data);
+                emit(ParsedMessage::Unparsed(partial));
+            }
+        }
+    }
+
+    fn flush_stray<F: FnMut(ParsedMessage)>(&mut self, emit: &mut F) {
+        if !self.stray.is_empty() {
+            emit(ParsedMessage::Unparsed(std::mem::take(&mut self.stray)));
+        }
+    }
+
+    fn complete_if_ready<F: FnMut(ParsedMessage)>(&mut self, emit: &mut F) {
+        let status = match self.status {
+            Some(status) => status,
+            None => return,
+        };
+        if self.data.len() < data_len(status) {
+            return;
+        }
+
+        let mut msg = vec![status];
+        msg.append(&mut self.data);
+        let event = if status < 0xf0 { ControllerEvent::from_midi(&msg) } else { None };
+        emit(match event {
+            Some(event) => ParsedMessage::Event(event),
+            None => ParsedMessage::Unparsed(msg),
+        });
+
+        // Only channel messages establish running status.
+        if status >= 0xf0 {
+            self.status = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::DiscoveryOptions;
+    use crate::input::ButtonState;
+    use crate::mock::TestFire;
+
+    fn pad(index: u8, velocity: u8) -> ControllerEvent {
+        ControllerEvent::GridButton(index, index / 16, index % 16, ButtonState::Down, velocity)
+    }
+
+    #[test]
+    fn running_status_carries_across_buffers() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.feed(&[0x90, 0x36, 0x7f, 0x37, 0x40]);
+        fire.feed(&[0x38, 0x10]);
+        assert_eq!(fire.events(), vec![pad(0, 0x7f), pad(1, 0x40), pad(2, 0x10)]);
+    }
+
+    #[test]
+    fn messages_split_across_buffers() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.feed(&[0x90]);
+        fire.feed(&[0x36]);
+        assert!(fire.events().is_empty());
+        fire.feed(&[0x7f, 0x90, 0x37]);
+        fire.feed(&[0x20]);
+        assert_eq!(fire.events(), vec![pad(0, 0x7f), pad(1, 0x20)]);
+    }
+
+    #[test]
+    fn realtime_bytes_mid_message() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.feed(&[0x90, 0xf8, 0x36, 0xfe, 0x7f, 0xf8]);
+        assert_eq!(fire.events(), vec![pad(0, 0x7f)]);
+
+        // The controller drops them, but they come out where they were.
+        let mut parsed = vec![];
+        MidiParser::new().feed(&[0x90, 0xf8, 0x36, 0xfe, 0x7f], |m| parsed.push(m));
+        assert_eq!(parsed, vec![ParsedMessage::Realtime(0xf8), ParsedMessage::Realtime(0xfe),
+                                ParsedMessage::Event(pad(0, 0x7f))]);
+    }
+
+    #[test]
+    fn sysex_split_across_buffers() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.feed(&[0xf0, 0x47, 0x7f]);
+        fire.feed(&[0xf8, 0x43]);
+        assert!(fire.events().is_empty());
+        fire.feed(&[0x01, 0xf7, 0x90, 0x36, 0x7f]);
+        assert_eq!(fire.events(), vec![
+            ControllerEvent::Unparsed(vec![0xf0, 0x47, 0x7f, 0x43, 0x01, 0xf7]),
+            pad(0, 0x7f),
+        ]);
+    }
+
+    #[test]
+    fn interrupted_and_stray_bytes_are_unparsed() {
+        let mut fire = TestFire::attach(&DiscoveryOptions::default());
+        fire.feed(&[0x12, 0x34]);
+        fire.feed(&[0xf0, 0x47, 0x90, 0x36, 0x7f]);
+        fire.feed(&[0x80, 0x36, 0xb0, 0x10, 0x01]);
+        assert_eq!(fire.events(), vec![
+            ControllerEvent::Unparsed(vec![0x12, 0x34]),
+            ControllerEvent::Unparsed(vec![0xf0, 0x47]),
+            pad(0, 0x7f),
+            ControllerEvent::Unparsed(vec![0x80, 0x36]),
+            ControllerEvent::KnobTurn(crate::input::ControllerKnob::Volume, 1),
+        ]);
+    }
+}
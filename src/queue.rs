@@ -0,0 +1,392 @@
+//! The channel carrying a controller's events from the MIDI thread to the
+//! application, with a choice of what to do when the application falls
+//! behind.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::stream::{Stream, StreamExt};
+
+use crate::input::{ControllerEvent, EventEnvelope};
+
+/// What to do with an event from the device when the channel is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stall the MIDI input thread until there's room.  Nothing is lost, but
+    /// the MIDI backend may drop or delay messages of its own meanwhile.
+    Block,
+    /// Drop the new event.
+    DropNewest,
+    /// Drop the oldest queued event to make room for the new one.
+    DropOldest,
+    /// Add knob turns to the latest queued turn of the same knob and layer,
+    /// so that no movement is lost, only its timing.  Other events are
+    /// dropped like with `DropNewest`.
+    CoalesceKnobs,
+    /// Never consider the channel full.  Memory use is up to the consumer
+    /// keeping up.
+    Unbounded,
+}
+
+struct Shared {
+    events: VecDeque<EventEnvelope>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Events dropped since the channel was created.
+    dropped: u64,
+    /// Whether events were dropped since the last `take_overflowed`.
+    overflowed: bool,
+    senders: usize,
+    receiver_alive: bool,
+    /// Whether events from the device are being turned away because its
+    /// input connection is about to be dropped.
+    input_closed: bool,
+    waker: Option<Waker>,
+}
+
+struct Channel {
+    shared: Mutex<Shared>,
+    /// Signalled when a blocked sender may be able to go ahead.
+    room: Condvar,
+}
+
+/// Creates a channel holding up to `capacity` events, handling overflow
+/// according to `policy`.
+pub(crate) fn channel(capacity: usize, policy: OverflowPolicy) -> (EventSender, EventReceiver) {
+    let channel = Arc::new(Channel {
+        shared: Mutex::new(Shared {
+            events: VecDeque::new(),
+            // A zero capacity would have `Block` wait forever.
+            capacity: capacity.max(1),
+            policy,
+            dropped: 0,
+            overflowed: false,
+            senders: 1,
+            receiver_alive: true,
+            input_closed: false,
+            waker: None,
+        }),
+        room: Condvar::new(),
+    });
+    (EventSender { channel: channel.clone() }, EventReceiver { channel })
+}
+
+/// The sending half of a controller's event channel.
+pub(crate) struct EventSender {
+    channel: Arc<Channel>,
+}
+
+impl EventSender {
+    /// Queues an event from the device, applying the overflow policy if the
+    /// channel is full.  Events for a dropped receiver or a closed input are
+    /// discarded.
+    pub(crate) fn send(&self, envelope: EventEnvelope) {
+        let mut shared = self.channel.shared.lock().unwrap();
+        if shared.policy == OverflowPolicy::Block {
+            while shared.receiver_alive && !shared.input_closed &&
+                shared.events.len() >= shared.capacity {
+                shared = self.channel.room.wait(shared).unwrap();
+            }
+        }
+        if !shared.receiver_alive || shared.input_closed {
+            return;
+        }
+        if shared.events.len() >= shared.capacity {
+            match shared.policy {
+                OverflowPolicy::Block | OverflowPolicy::Unbounded => (),
+                OverflowPolicy::DropNewest => {
+                    shared.note_dropped();
+                    return;
+                },
+                OverflowPolicy::DropOldest => {
+                    shared.events.pop_front();
+                    shared.note_dropped();
+                },
+                OverflowPolicy::CoalesceKnobs => {
+                    if !shared.coalesce(&envelope) {
+                        shared.note_dropped();
+                    }
+                    return;
+                },
+            }
+        }
+        shared.push(envelope);
+    }
+
+    /// Queues an event regardless of the capacity.  For the few events the
+    /// controller generates itself, which shouldn't be turned away or block.
+    pub(crate) fn force_send(&self, envelope: EventEnvelope) {
+        let mut shared = self.channel.shared.lock().unwrap();
+        if shared.receiver_alive {
+            shared.push(envelope);
+        }
+    }
+
+    /// Turns away events from the device until `open_input`, releasing a
+    /// `send` blocked on a full channel.  Dropping an input connection waits
+    /// for its callback to return, so this has to come first.
+    pub(crate) fn close_input(&self) {
+        self.channel.shared.lock().unwrap().input_closed = true;
+        self.channel.room.notify_all();
+    }
+
+    /// Accepts events from the device again, for a new input connection.
+    pub(crate) fn open_input(&self) {
+        self.channel.shared.lock().unwrap().input_closed = false;
+    }
+
+    /// Number of events dropped since the channel was created.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.channel.shared.lock().unwrap().dropped
+    }
+
+    /// Whether events were dropped since the last call.
+    pub(crate) fn take_overflowed(&self) -> bool {
+        std::mem::replace(&mut self.channel.shared.lock().unwrap().overflowed, false)
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.channel.shared.lock().unwrap().senders += 1;
+        EventSender { channel: self.channel.clone() }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut shared = self.channel.shared.lock().unwrap();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            // Let the receiver see the end of the stream.
+            shared.wake();
+        }
+    }
+}
+
+impl Shared {
+    fn push(&mut self, envelope: EventEnvelope) {
+        self.events.push_back(envelope);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn note_dropped(&mut self) {
+        self.dropped += 1;
+        self.overflowed = true;
+    }
+
+    /// Adds a knob turn to the latest queued turn of the same knob.  Returns
+    /// false if the event isn't a knob turn or there's nothing to add it to.
+    fn coalesce(&mut self, envelope: &EventEnvelope) -> bool {
+        let (layer, knob, delta) = match envelope.event {
+            ControllerEvent::KnobTurn(knob, delta) => (None, knob, delta),
+            ControllerEvent::LayerKnobTurn(layer, knob, delta) => (Some(layer), knob, delta),
+            _ => return false,
+        };
+        let latest = self.events.iter_mut().rev().find_map(|queued| match &mut queued.event {
+            ControllerEvent::KnobTurn(k, d) if layer.is_none() && *k == knob => Some(d),
+            ControllerEvent::LayerKnobTurn(l, k, d) if layer == Some(*l) && *k == knob => Some(d),
+            _ => None,
+        });
+        match latest {
+            Some(d) => {
+                *d = d.saturating_add(delta);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// The receiving half of a controller's event channel, see
+/// `FireController::take_event_rx`.
+///
+/// The stream ends once the controller and everything it handed out is gone.
+pub struct EventReceiver {
+    channel: Arc<Channel>,
+}
+
+impl EventReceiver {
+    /// Takes the next event if there is one, without waiting.
+    pub fn try_recv(&mut self) -> Option<EventEnvelope> {
+        let event = self.channel.shared.lock().unwrap().events.pop_front();
+        if event.is_some() {
+            self.channel.room.notify_all();
+        }
+        event
+    }
+
+    /// Waits for the next event.  Returns None once the stream has ended.
+    pub async fn recv(&mut self) -> Option<EventEnvelope> {
+        self.next().await
+    }
+
+    /// Number of events queued and not yet received.
+    pub fn len(&self) -> usize {
+        self.channel.shared.lock().unwrap().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Stream for EventReceiver {
+    type Item = EventEnvelope;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<EventEnvelope>> {
+        let mut shared = self.channel.shared.lock().unwrap();
+        if let Some(envelope) = shared.events.pop_front() {
+            self.channel.room.notify_all();
+            Poll::Ready(Some(envelope))
+        } else if shared.senders == 0 {
+            Poll::Ready(None)
+        } else {
+            shared.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        let mut shared = self.channel.shared.lock().unwrap();
+        shared.receiver_alive = false;
+        shared.events.clear();
+        // Nobody is going to make room for blocked senders anymore.
+        self.channel.room.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceId;
+    use crate::discovery::DiscoveryOptions;
+    use crate::error::FireError;
+    use crate::input::{ButtonState, ControllerKnob};
+    use crate::mock::TestFire;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn envelope(event: ControllerEvent) -> EventEnvelope {
+        EventEnvelope { device: DeviceId::Enumerated(0), stamp: 0, received: Instant::now(), event }
+    }
+
+    #[test]
+    fn closing_the_input_releases_a_blocked_send() {
+        let (tx, mut rx) = channel(1, OverflowPolicy::Block);
+        tx.send(envelope(ControllerEvent::Connected));
+        let (done_tx, done_rx) = mpsc::channel();
+        let sender = tx.clone();
+        thread::spawn(move || {
+            sender.send(envelope(ControllerEvent::Disconnected));
+            done_tx.send(()).unwrap();
+        });
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        tx.close_input();
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(rx.try_recv().unwrap().event, ControllerEvent::Connected);
+        assert!(rx.try_recv().is_none());
+
+        tx.open_input();
+        tx.send(envelope(ControllerEvent::Disconnected));
+        assert_eq!(rx.try_recv().unwrap().event, ControllerEvent::Disconnected);
+    }
+
+    fn attach(policy: OverflowPolicy) -> TestFire {
+        TestFire::attach(&DiscoveryOptions::default()
+            .with_channel_capacity(2)
+            .with_overflow_policy(policy))
+    }
+
+    fn pad(index: u8) -> ControllerEvent {
+        ControllerEvent::GridButton(index, 0, index, ButtonState::Down, 0x7f)
+    }
+
+    /// Presses pads 0..4, one message at a time.
+    fn press_four(fire: &TestFire) {
+        for note in 0x36..0x3a {
+            fire.feed(&[0x90, note, 0x7f]);
+        }
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_first_events() {
+        let mut fire = attach(OverflowPolicy::DropNewest);
+        press_four(&fire);
+        assert_eq!(fire.events(), vec![pad(0), pad(1)]);
+        assert_eq!(fire.controller.dropped_events(), 2);
+        assert!(matches!(fire.controller.check_input(), Err(FireError::ChannelOverflow)));
+        assert!(fire.controller.check_input().is_ok());
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_last_events() {
+        let mut fire = attach(OverflowPolicy::DropOldest);
+        press_four(&fire);
+        assert_eq!(fire.events(), vec![pad(2), pad(3)]);
+        assert_eq!(fire.controller.dropped_events(), 2);
+    }
+
+    #[test]
+    fn coalesce_knobs_adds_up_turns() {
+        let mut fire = attach(OverflowPolicy::CoalesceKnobs);
+        fire.feed(&[0xb0, 0x10, 0x01, 0xb0, 0x11, 0x01]);
+        // Full from here on.
+        fire.feed(&[0xb0, 0x10, 0x02, 0xb0, 0x10, 0x7f, 0xb0, 0x11, 0x03]);
+        // Nothing to add these to.
+        fire.feed(&[0xb0, 0x12, 0x01, 0x90, 0x36, 0x7f]);
+        assert_eq!(fire.events(), vec![
+            ControllerEvent::KnobTurn(ControllerKnob::Volume, 2),
+            ControllerEvent::KnobTurn(ControllerKnob::Pan, 4),
+        ]);
+        assert_eq!(fire.controller.dropped_events(), 2);
+    }
+
+    #[test]
+    fn unbounded_keeps_everything() {
+        let mut fire = attach(OverflowPolicy::Unbounded);
+        press_four(&fire);
+        assert_eq!(fire.events(), vec![pad(0), pad(1), pad(2), pad(3)]);
+        assert_eq!(fire.controller.dropped_events(), 0);
+        assert!(fire.controller.check_input().is_ok());
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let mut fire = attach(OverflowPolicy::Block);
+        let (transport, input) = (fire.transport.clone(), fire.input);
+        let feeder = thread::spawn(move || {
+            for note in 0x36..0x3a {
+                transport.feed_input(input, 0, &[0x90, note, 0x7f]);
+            }
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fire.events.len() < 2 {
+            assert!(Instant::now() < deadline);
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(50));
+        // The third press is waiting.
+        assert_eq!(fire.events.len(), 2);
+
+        let mut events = vec![];
+        while events.len() < 4 {
+            assert!(Instant::now() < deadline);
+            events.extend(fire.events());
+        }
+        feeder.join().unwrap();
+        assert_eq!(events, vec![pad(0), pad(1), pad(2), pad(3)]);
+        assert_eq!(fire.controller.dropped_events(), 0);
+    }
+}
@@ -0,0 +1,107 @@
+//! What we believe the device is currently showing.
+
+use crate::command::{ControllerCommand, PadColor};
+use crate::input::{ChannelMode, ControllerButton};
+use crate::oled::OledBitmap;
+use crate::output::{self, ButtonLedState, PAD_COUNT};
+
+/// Mirror of the device's visible state, built from every command sent to it.
+///
+/// The device can't be asked what it's showing, and it forgets everything
+/// when it loses power, so this is what gets replayed to bring it back in
+/// line with what the application thinks it shows.
+#[derive(Clone)]
+pub struct DeviceShadow {
+    pads: [[u8; 3]; PAD_COUNT],
+    /// Last value sent to each CC-addressed LED, or None if never set.
+    cc_leds: [Option<u8>; 128],
+    oled: OledBitmap,
+}
+
+impl DeviceShadow {
+    pub fn new() -> Self {
+        DeviceShadow {
+            pads: [[0; 3]; PAD_COUNT],
+            cc_leds: [None; 128],
+            oled: OledBitmap::new(),
+        }
+    }
+
+    /// Records the effect of a command that was sent to the device.
+    pub fn apply(&mut self, command: &ControllerCommand) {
+        match command {
+            ControllerCommand::PadColors(pads) => {
+                for pad in pads {
+                    if let Some(color) = self.pads.get_mut(pad.index as usize) {
+                        *color = [pad.r, pad.g, pad.b];
+                    }
+                }
+            },
+            ControllerCommand::ButtonLed { cc, value } => {
+                if let Some(led) = self.cc_leds.get_mut(*cc as usize) {
+                    *led = Some(*value);
+                }
+            },
+            ControllerCommand::OledWrite { .. } => self.oled.apply(command),
+            ControllerCommand::IdentityRequest => (),
+        }
+    }
+
+    /// The color of pad `index` as (r, g, b).
+    pub fn pad(&self, index: usize) -> Option<[u8; 3]> {
+        self.pads.get(index).copied()
+    }
+
+    /// The last value sent to the LED with the given CC number.
+    pub fn cc_led(&self, cc: u8) -> Option<u8> {
+        self.cc_leds.get(cc as usize).copied().flatten()
+    }
+
+    /// What the button's LED was last set to, or None if it was never set or
+    /// the button has no LED.
+    pub fn button_led(&self, button: ControllerButton) -> Option<ButtonLedState> {
+        self.cc_led(button.note()).and_then(|value| button.led_state(value))
+    }
+
+    /// What the LED next to track row `row` was last set to.
+    pub fn track_led(&self, row: u8) -> Option<ButtonLedState> {
+        if row >= output::TRACK_ROWS {
+            return None;
+        }
+        self.cc_led(output::TRACK_LED_CC + row).and_then(output::track_led_state)
+    }
+
+    /// Which mode indicator LED is lit, if any.
+    pub fn mode_led(&self) -> Option<ChannelMode> {
+        self.cc_led(output::MODE_LED_CC).and_then(output::mode_led_state)
+    }
+
+    pub fn oled(&self) -> &OledBitmap {
+        &self.oled
+    }
+
+    /// The command setting every pad to its color.
+    pub fn pads_command(&self) -> ControllerCommand {
+        ControllerCommand::PadColors(self.pads.iter().enumerate().map(|(i, &[r, g, b])| {
+            PadColor { index: i as u8, r, g, b }
+        }).collect())
+    }
+
+    /// The commands that bring a blank device to this state.
+    pub fn replay(&self) -> Vec<ControllerCommand> {
+        let mut commands = vec![self.pads_command()];
+        for (cc, value) in self.cc_leds.iter().enumerate() {
+            if let Some(value) = value {
+                commands.push(ControllerCommand::ButtonLed { cc: cc as u8, value: *value });
+            }
+        }
+        commands.push(self.oled.to_command());
+        commands
+    }
+}
+
+impl Default for DeviceShadow {
+    fn default() -> Self {
+        DeviceShadow::new()
+    }
+}
@@ -0,0 +1,131 @@
+//! Abstraction over the MIDI backend so that controllers can be driven by
+//! something other than real hardware.  `MidirTransport` is the default
+//! implementation; `crate::mock::MockTransport` is an in-memory one.
+//!
+//! Ports are identified by their index into the lists returned by
+//! `input_port_names` and `output_port_names` because several ports may share
+//! the same name.
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+
+use crate::error::{FireError, Result};
+
+/// Receives the backend's microsecond timestamp and the raw bytes of each
+/// incoming message.  Invoked on a backend-owned thread.
+pub type InputCallback = Box<dyn FnMut(u64, &[u8]) + Send + 'static>;
+
+pub trait MidiTransport {
+    /// Names of all the MIDI input ports currently on the system.
+    fn input_port_names(&self) -> Result<Vec<String>>;
+
+    /// Names of all the MIDI output ports currently on the system.
+    fn output_port_names(&self) -> Result<Vec<String>>;
+
+    /// Connects to input port `port`, invoking `callback` for each message
+    /// until the returned connection is dropped.
+    fn connect_input(&self, port: usize, callback: InputCallback)
+        -> Result<Box<dyn InputConnection>>;
+
+    /// Connects to output port `port`.
+    fn connect_output(&self, port: usize) -> Result<Box<dyn OutputConnection>>;
+}
+
+/// An open input connection.  Dropping it closes the connection.
+pub trait InputConnection: Send {}
+
+/// An open output connection.  Dropping it closes the connection.
+pub trait OutputConnection: Send {
+    fn send(&mut self, msg: &[u8]) -> Result<()>;
+}
+
+/// The real thing, backed by `midir`.
+#[derive(Clone, Debug)]
+pub struct MidirTransport {
+    input_client_name: String,
+    output_client_name: String,
+}
+
+impl MidirTransport {
+    pub fn new(input_client_name: &str, output_client_name: &str) -> Self {
+        MidirTransport {
+            input_client_name: input_client_name.to_string(),
+            output_client_name: output_client_name.to_string(),
+        }
+    }
+}
+
+impl Default for MidirTransport {
+    fn default() -> Self {
+        MidirTransport::new("Fire-Walk", "Fire")
+    }
+}
+
+impl MidiTransport for MidirTransport {
+    fn input_port_names(&self) -> Result<Vec<String>> {
+        let midi_in = MidiInput::new(&self.input_client_name)?;
+        let mut names = vec![];
+        for p in midi_in.ports() {
+            names.push(midi_in.port_name(&p)?);
+        }
+        Ok(names)
+    }
+
+    fn output_port_names(&self) -> Result<Vec<String>> {
+        let midi_out = MidiOutput::new(&self.output_client_name)?;
+        let mut names = vec![];
+        for p in midi_out.ports() {
+            names.push(midi_out.port_name(&p)?);
+        }
+        Ok(names)
+    }
+
+    fn connect_input(&self, port: usize, mut callback: InputCallback)
+        -> Result<Box<dyn InputConnection>> {
+        // Calling connect() on a MidiInput consumes (moves) it, so each
+        // connection gets a MidiInput of its own.
+        let midi_in = MidiInput::new(&self.input_client_name)?;
+        let in_port = midi_in.ports().into_iter().nth(port)
+            .ok_or_else(|| missing_port(port))?;
+        let port_name = midi_in.port_name(&in_port)?;
+        let conn = midi_in.connect(
+            &in_port, "fire-in", move |stamp, msg, _| callback(stamp, msg), ())
+            .map_err(|e| FireError::Connect {
+                port: port_name,
+                reason: e.to_string(),
+            })?;
+        Ok(Box::new(MidirInputConnection(conn)))
+    }
+
+    fn connect_output(&self, port: usize) -> Result<Box<dyn OutputConnection>> {
+        let midi_out = MidiOutput::new(&self.output_client_name)?;
+        let out_port = midi_out.ports().into_iter().nth(port)
+            .ok_or_else(|| missing_port(port))?;
+        let port_name = midi_out.port_name(&out_port)?;
+        let conn = midi_out.connect(&out_port, "fire-out")
+            .map_err(|e| FireError::Connect {
+                port: port_name,
+                reason: e.to_string(),
+            })?;
+        Ok(Box::new(MidirOutputConnection(conn)))
+    }
+}
+
+struct MidirInputConnection(#[allow(dead_code)] MidiInputConnection<()>);
+
+impl InputConnection for MidirInputConnection {}
+
+struct MidirOutputConnection(MidiOutputConnection);
+
+impl OutputConnection for MidirOutputConnection {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.0.send(msg)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn missing_port(port: usize) -> FireError {
+    FireError::Connect {
+        port: format!("#{}", port),
+        reason: "port not found".to_string(),
+    }
+}
@@ -0,0 +1,874 @@
+//! Sending commands to a device from any number of threads.
+//!
+//! Each controller has a writer thread that owns the connection to the
+//! device's output port.  `OutputHandle`s queue commands for it, so that
+//! everything sent to a device goes out one message at a time and in the
+//! order it was queued.
+//!
+//! The writer only sends the pads and LEDs that differ from what the device is
+//! known to show, or the whole grid if most of the pads do.  What the device
+//! shows is unknown after connecting and after failures, until it's sent
+//! again.  Calibration and brightness are applied on the way out, so the
+//! shadow state keeps the values the application set.
+//!
+//! Optionally, the writer batches up the changes to the pads, button LEDs and
+//! OLED and sends them as frames at a fixed rate, so that an application can
+//! draw after every event without flooding the device.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::brightness::{self, Brightness, LedRegion};
+use crate::calibration::Calibration;
+use crate::color::Color;
+use crate::command::{ControllerCommand, PadColor};
+use crate::error::{FireError, Result};
+use crate::input::{ChannelMode, ControllerButton};
+use crate::oled::OledBitmap;
+use crate::output::{self, ButtonLedState, PadLedBuffer, PAD_COUNT};
+use crate::shadow::DeviceShadow;
+use crate::transport::OutputConnection;
+
+enum Message {
+    /// A command along with its encoding.
+    Command(ControllerCommand, Vec<u8>),
+    /// Start sending to a new connection.  Acknowledged once everything
+    /// queued before it was dealt with.
+    Connect(Box<dyn OutputConnection>, mpsc::Sender<()>),
+    Disconnect,
+    /// Send the whole shadow state again.
+    Resync,
+    /// Send the pads again after a calibration change.
+    Recalibrate,
+    /// Show a command's pads or CC LED instead of the shadow state's.
+    Overlay(ControllerCommand),
+    ClearOverlay,
+    /// Fade the master brightness, or a region's, to a level.
+    SetBrightness(Option<LedRegion>, f64, Duration),
+    /// Batch changes into frames sent this often, or stop batching.
+    SetFrameInterval(Option<Duration>),
+    /// Send the changes batched so far right away.
+    Present,
+    /// Report the first failure since the last flush once everything queued
+    /// before it was sent.
+    Flush(mpsc::Sender<Result<()>>),
+}
+
+/// How often fades are stepped when not batching into frames.
+const FADE_INTERVAL: Duration = Duration::from_millis(16);
+
+/// How the frames sent by the scheduler went, see
+/// `OutputHandle::set_frame_interval`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames sent.
+    pub frames: u64,
+    /// Bytes of MIDI sent in those frames.
+    pub bytes: u64,
+    /// Summed over all frames, the time from the first change in a frame to
+    /// the frame being sent.
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    /// Summed over all frames, the time it took to hand a frame to the MIDI
+    /// backend.
+    pub total_write_time: Duration,
+    pub max_write_time: Duration,
+}
+
+impl FrameStats {
+    /// How long changes waited for their frame on average.
+    pub fn mean_latency(&self) -> Duration {
+        self.mean(self.total_latency)
+    }
+
+    /// How long sending a frame took on average.
+    pub fn mean_write_time(&self) -> Duration {
+        self.mean(self.total_write_time)
+    }
+
+    fn mean(&self, total: Duration) -> Duration {
+        if self.frames == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos((total.as_nanos() / self.frames as u128) as u64)
+        }
+    }
+
+    fn record(&mut self, bytes: usize, latency: Duration, write_time: Duration) {
+        self.frames += 1;
+        self.bytes += bytes as u64;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+        self.total_write_time += write_time;
+        self.max_write_time = self.max_write_time.max(write_time);
+    }
+}
+
+/// A cheaply cloneable way to draw to a controller's device from any thread.
+///
+/// Commands are recorded in the shadow state right away and queued for the
+/// controller's writer thread, which sends them to the device.  Sending is
+/// asynchronous, so failures to send show up in `flush` rather than in the
+/// methods queueing the commands.  A failure to send is taken to mean the
+/// device went away, so the controller disconnects.
+///
+/// Handles keep working across reconnects, and outliving their controller is
+/// harmless; commands then just end up in the shadow state.
+#[derive(Clone)]
+pub struct OutputHandle {
+    tx: Arc<Mutex<mpsc::Sender<Message>>>,
+    /// What the device should be showing given everything sent to it.
+    shadow: Arc<Mutex<DeviceShadow>>,
+    /// Whether the writer has a connection to send to.
+    connected: Arc<AtomicBool>,
+    stats: Arc<Mutex<FrameStats>>,
+    calibration: Arc<Mutex<Calibration>>,
+    /// The master level the writer is fading to.
+    brightness: Arc<Mutex<f64>>,
+}
+
+impl OutputHandle {
+    /// Starts a writer thread without a connection.  `on_lost` is called on
+    /// that thread when sending fails and the connection is dropped.
+    pub(crate) fn spawn(on_lost: Box<dyn FnMut() + Send>) -> OutputHandle {
+        let (tx, rx) = mpsc::channel();
+        let shadow = Arc::new(Mutex::new(DeviceShadow::new()));
+        let connected = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Mutex::new(FrameStats::default()));
+        let calibration = Arc::new(Mutex::new(Calibration::default()));
+        let writer = Writer {
+            connection: None,
+            sent_pads: [None; PAD_COUNT],
+            sent_cc_leds: [None; 128],
+            dirty_pads: [false; PAD_COUNT],
+            dirty_cc_leds: [false; 128],
+            dirty_oled: false,
+            overlay_pads: [None; PAD_COUNT],
+            overlay_cc_leds: [None; 128],
+            frame_interval: None,
+            pending_since: None,
+            last_frame: None,
+            brightness: Brightness::new(),
+            brightness_changed: false,
+            buf: Vec::new(),
+            error: None,
+            shadow: shadow.clone(),
+            connected: connected.clone(),
+            stats: stats.clone(),
+            calibration: calibration.clone(),
+            on_lost,
+        };
+        thread::spawn(move || writer.run(rx));
+        OutputHandle {
+            tx: Arc::new(Mutex::new(tx)),
+            shadow,
+            connected,
+            stats,
+            calibration,
+            brightness: Arc::new(Mutex::new(1.0)),
+        }
+    }
+
+    /// Queues a single command.  Valid commands are recorded in the shadow
+    /// state even if they can't be sent, so that they take effect once the
+    /// device is back, but `FireError::NotConnected` is still reported.
+    pub fn send_command(&self, command: &ControllerCommand) -> Result<()> {
+        let bytes = encode_checked(command)?;
+        {
+            // Held until the command is queued, so that the writer gets the
+            // commands of all the handles in the order they hit the shadow.
+            let mut shadow = self.shadow.lock().unwrap();
+            shadow.apply(command);
+            self.post(Message::Command(command.clone(), bytes))?;
+        }
+        if self.is_connected() {
+            Ok(())
+        } else {
+            Err(FireError::NotConnected)
+        }
+    }
+
+    /// Sets the color of a single pad.  The components are clamped to
+    /// 0..=0x7f.
+    pub fn set_pad(&self, index: u8, r: u8, g: u8, b: u8) -> Result<()> {
+        if index as usize >= PAD_COUNT {
+            return Err(FireError::InvalidArgument(
+                format!("pad index {} is not less than {}", index, PAD_COUNT)));
+        }
+        let color = PadColor { index, r: r.min(0x7f), g: g.min(0x7f), b: b.min(0x7f) };
+        self.send_command(&ControllerCommand::PadColors(vec![color]))
+    }
+
+    /// Sets a single pad to look like an 8-bit screen color, see
+    /// `Color::to_fire`.
+    pub fn set_pad_color(&self, index: u8, color: Color) -> Result<()> {
+        let [r, g, b] = color.to_fire();
+        self.set_pad(index, r, g, b)
+    }
+
+    /// Sets the colors of all the pads.
+    pub fn draw_pads(&self, leds: &PadLedBuffer) -> Result<()> {
+        self.send_command(&leds.to_command())
+    }
+
+    /// Sets a button's LED, see `ControllerButton::led_states` for what each
+    /// button can show.
+    pub fn set_button_led(&self, button: ControllerButton, state: ButtonLedState) -> Result<()> {
+        self.send_command(&button.led_command(state)?)
+    }
+
+    /// Sets the LED next to track row `row` (0-based, top to bottom), see
+    /// `output::track_led_states` for what it can show.
+    pub fn set_track_led(&self, row: u8, state: ButtonLedState) -> Result<()> {
+        self.send_command(&output::track_led_command(row, state)?)
+    }
+
+    /// Lights the indicator LED for `mode`, or none of them.
+    pub fn set_mode_led(&self, mode: Option<ChannelMode>) -> Result<()> {
+        self.send_command(&output::mode_led_command(mode))
+    }
+
+    /// Sets the LED with the given CC number to a raw value.
+    pub fn set_cc_led(&self, cc: u8, value: u8) -> Result<()> {
+        self.send_command(&ControllerCommand::ButtonLed { cc, value })
+    }
+
+    /// Draws the whole bitmap to the OLED.
+    pub fn draw_oled(&self, bitmap: &OledBitmap) -> Result<()> {
+        self.send_command(&bitmap.to_command())
+    }
+
+    /// Sends the shadow state to the device again, for when the device may
+    /// have lost it, such as after a power glitch.
+    pub fn resync(&self) -> Result<()> {
+        self.post(Message::Resync)?;
+        if self.is_connected() {
+            Ok(())
+        } else {
+            Err(FireError::NotConnected)
+        }
+    }
+
+    /// Turns on batching changes to the pads, button LEDs and OLED into
+    /// frames sent at most once per `interval`, such as 1/60th of a second,
+    /// or turns it off with None.  Changes made after a quiet spell go out
+    /// right away.  Off by default.
+    pub fn set_frame_interval(&self, interval: Option<Duration>) -> Result<()> {
+        self.post(Message::SetFrameInterval(interval))
+    }
+
+    /// Sends the changes batched so far without waiting for the next frame.
+    pub fn present(&self) -> Result<()> {
+        self.post(Message::Present)
+    }
+
+    /// Timing of the frames sent so far.
+    pub fn frame_stats(&self) -> FrameStats {
+        *self.stats.lock().unwrap()
+    }
+
+    pub fn reset_frame_stats(&self) {
+        *self.stats.lock().unwrap() = FrameStats::default();
+    }
+
+    /// Sets the corrections applied to the pad colors on their way to the
+    /// device, and sends the pads the device is showing again with them.
+    pub fn set_calibration(&self, calibration: Calibration) -> Result<()> {
+        *self.calibration.lock().unwrap() = calibration;
+        self.post(Message::Recalibrate)
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        *self.calibration.lock().unwrap()
+    }
+
+    /// Fades the brightness of every pad and button LED to `level`, from 0.0
+    /// for dark to 1.0 for the colors as set, over `fade`.  The fade is
+    /// stepped with the frames, see `set_frame_interval`, or about 60 times
+    /// a second when not batching.  The button LEDs only have two levels, so
+    /// they go dull below half brightness and off near the bottom.
+    pub fn set_brightness(&self, level: f64, fade: Duration) -> Result<()> {
+        *self.brightness.lock().unwrap() = brightness::clamp_level(level, 1.0);
+        self.post(Message::SetBrightness(None, level, fade))
+    }
+
+    /// The master level last set with `set_brightness`.
+    pub fn brightness(&self) -> f64 {
+        *self.brightness.lock().unwrap()
+    }
+
+    /// Fades the multiplier for `region` to `level` over `fade`, on top of
+    /// the master brightness and any overlapping regions.  Levels above 1.0
+    /// make the region stand out on a dimmed device, up to
+    /// `brightness::MAX_REGION_LEVEL`; pad colors are capped at full
+    /// brightness.  Fading a region back to 1.0 removes it.
+    pub fn set_region_brightness(&self, region: LedRegion, level: f64, fade: Duration)
+                                 -> Result<()> {
+        self.post(Message::SetBrightness(Some(region), level, fade))
+    }
+
+    /// Waits until everything queued so far was sent, including any batched
+    /// changes, and reports the first failure to send since the last flush.
+    pub fn flush(&self) -> Result<()> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.post(Message::Flush(reply_tx))?;
+        reply_rx.recv().map_err(|_| FireError::NotConnected)?
+    }
+
+    /// Whether the writer currently has a connection to the device.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// A copy of what the device should be showing given everything sent to
+    /// it so far.
+    pub fn shadow(&self) -> DeviceShadow {
+        self.shadow.lock().unwrap().clone()
+    }
+
+    /// Hands the writer a new connection, once it's done with everything
+    /// queued before.
+    pub(crate) fn connect(&self, connection: Box<dyn OutputConnection>) -> Result<()> {
+        let (ack_tx, ack_rx) = mpsc::channel();
+        self.post(Message::Connect(connection, ack_tx))?;
+        ack_rx.recv().map_err(|_| FireError::NotConnected)
+    }
+
+    /// Has the writer drop its connection after sending everything queued so
+    /// far.  Returns whether it was connected, which is false if the
+    /// connection was already lost.
+    pub(crate) fn disconnect(&self) -> bool {
+        let was_connected = self.connected.swap(false, Ordering::SeqCst);
+        // The writer only goes away once every handle is gone.
+        let _ = self.post(Message::Disconnect);
+        was_connected
+    }
+
+    /// Shows the pads or the CC LED of a `PadColors` or `ButtonLed` command
+    /// instead of what the shadow state has for them, until `clear_overlay`.
+    /// The shadow state doesn't see any of it.  Other commands are ignored.
+    pub(crate) fn overlay(&self, command: &ControllerCommand) -> Result<()> {
+        encode_checked(command)?;
+        self.post(Message::Overlay(command.clone()))
+    }
+
+    /// Goes back to showing the shadow state everywhere.
+    pub(crate) fn clear_overlay(&self) -> Result<()> {
+        self.post(Message::ClearOverlay)
+    }
+
+    fn post(&self, message: Message) -> Result<()> {
+        self.tx.lock().unwrap().send(message).map_err(|_| FireError::NotConnected)
+    }
+}
+
+/// Encodes a command, failing if it isn't valid for the device.
+fn encode_checked(command: &ControllerCommand) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    command.encode(&mut bytes)?;
+    // The encoding allows any 7-bit index, but the grid stops short.
+    if let ControllerCommand::PadColors(pads) = command {
+        if let Some(pad) = pads.iter().find(|pad| pad.index as usize >= PAD_COUNT) {
+            return Err(FireError::InvalidArgument(
+                format!("pad index {} is not less than {}", pad.index, PAD_COUNT)));
+        }
+    }
+    Ok(bytes)
+}
+
+struct Writer {
+    connection: Option<Box<dyn OutputConnection>>,
+    /// What each pad and CC LED is known to show, after calibration and
+    /// brightness.
+    sent_pads: [Option<[u8; 3]>; PAD_COUNT],
+    sent_cc_leds: [Option<u8>; 128],
+    /// What may need sending again since the last frame.
+    dirty_pads: [bool; PAD_COUNT],
+    dirty_cc_leds: [bool; 128],
+    dirty_oled: bool,
+    /// What to show instead of the shadow state, see `OutputHandle::overlay`.
+    overlay_pads: [Option<[u8; 3]>; PAD_COUNT],
+    overlay_cc_leds: [Option<u8>; 128],
+    /// How often to send frames, if batching.
+    frame_interval: Option<Duration>,
+    /// When the first change since the last frame came in.
+    pending_since: Option<Instant>,
+    last_frame: Option<Instant>,
+    brightness: Brightness,
+    /// Whether the brightness changed since the last frame, which is the case
+    /// throughout a fade.
+    brightness_changed: bool,
+    /// Reused for encoding the commands the writer puts together itself.
+    buf: Vec<u8>,
+    /// The first failure to send since the last flush.
+    error: Option<FireError>,
+    shadow: Arc<Mutex<DeviceShadow>>,
+    connected: Arc<AtomicBool>,
+    stats: Arc<Mutex<FrameStats>>,
+    /// Applied to pads as they're encoded, so the shadow doesn't see it.
+    calibration: Arc<Mutex<Calibration>>,
+    on_lost: Box<dyn FnMut() + Send>,
+}
+
+impl Writer {
+    /// Handles messages and sends frames until every handle is gone.
+    fn run(mut self, rx: mpsc::Receiver<Message>) {
+        loop {
+            let message = match self.frame_deadline() {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        self.send_frame();
+                        continue;
+                    }
+                    match rx.recv_timeout(deadline - now) {
+                        Ok(message) => message,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                },
+                None => match rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+            };
+            self.handle(message);
+        }
+        if self.pending_since.is_some() {
+            self.send_frame();
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Command(command, bytes) => self.command(&command, &bytes),
+            Message::Connect(connection, ack) => {
+                self.connection = Some(connection);
+                self.forget_sent();
+                self.connected.store(true, Ordering::SeqCst);
+                let _ = ack.send(());
+            },
+            Message::Disconnect => self.connection = None,
+            Message::Resync => {
+                self.forget_sent();
+                {
+                    let shadow = self.shadow.lock().unwrap();
+                    self.dirty_pads = [true; PAD_COUNT];
+                    for cc in 0..128u8 {
+                        self.dirty_cc_leds[cc as usize] = shadow.cc_led(cc).is_some()
+                            || self.overlay_cc_leds[cc as usize].is_some();
+                    }
+                }
+                self.dirty_oled = true;
+                self.pending_since = None;
+                self.send_changes(Instant::now());
+            },
+            Message::Recalibrate => {
+                self.mark_sent_dirty();
+                self.changed();
+            },
+            Message::Overlay(command) => {
+                match command {
+                    ControllerCommand::PadColors(pads) => {
+                        for pad in pads {
+                            let i = pad.index as usize;
+                            if let Some(overlay) = self.overlay_pads.get_mut(i) {
+                                *overlay = Some([pad.r, pad.g, pad.b]);
+                                self.dirty_pads[i] = true;
+                            }
+                        }
+                    },
+                    ControllerCommand::ButtonLed { cc, value } => {
+                        let cc = cc as usize;
+                        if let Some(overlay) = self.overlay_cc_leds.get_mut(cc) {
+                            *overlay = Some(value);
+                            self.dirty_cc_leds[cc] = true;
+                        }
+                    },
+                    _ => return,
+                }
+                self.changed();
+            },
+            Message::ClearOverlay => {
+                for (overlay, dirty) in self.overlay_pads.iter_mut().zip(&mut self.dirty_pads) {
+                    *dirty |= overlay.take().is_some();
+                }
+                for (overlay, dirty) in
+                    self.overlay_cc_leds.iter_mut().zip(&mut self.dirty_cc_leds) {
+                    *dirty |= overlay.take().is_some();
+                }
+                self.changed();
+            },
+            Message::SetBrightness(region, level, fade) => {
+                let now = Instant::now();
+                match region {
+                    Some(region) => self.brightness.set_region(region, level, fade, now),
+                    None => self.brightness.set_master(level, fade, now),
+                }
+                self.brightness_changed = true;
+            },
+            Message::SetFrameInterval(interval) => {
+                self.frame_interval = interval;
+                if interval.is_none() && self.pending_since.is_some() {
+                    self.send_frame();
+                }
+            },
+            Message::Present => {
+                if self.pending_since.is_some() {
+                    self.send_frame();
+                }
+            },
+            Message::Flush(reply) => {
+                if self.pending_since.is_some() {
+                    self.send_frame();
+                }
+                let _ = reply.send(self.error.take().map_or(Ok(()), Err));
+            },
+        }
+    }
+
+    /// Sends a command already recorded in the shadow, or leaves it for the
+    /// next frame.
+    fn command(&mut self, command: &ControllerCommand, bytes: &[u8]) {
+        let batching = self.frame_interval.is_some();
+        match *command {
+            ControllerCommand::PadColors(ref pads) => {
+                for pad in pads {
+                    if let Some(dirty) = self.dirty_pads.get_mut(pad.index as usize) {
+                        *dirty = true;
+                    }
+                }
+            },
+            ControllerCommand::ButtonLed { cc, .. } => {
+                if let Some(dirty) = self.dirty_cc_leds.get_mut(cc as usize) {
+                    *dirty = true;
+                }
+            },
+            ControllerCommand::OledWrite { .. } if batching => self.dirty_oled = true,
+            _ => {
+                self.send(bytes);
+                return;
+            },
+        }
+        self.changed();
+    }
+
+    /// When the next frame is due, if there's anything to send.  Fades step
+    /// with the frames, or at `FADE_INTERVAL` when not batching.
+    fn frame_deadline(&self) -> Option<Instant> {
+        let interval = match self.frame_interval {
+            Some(interval) if self.pending_since.is_some() || self.brightness_changed => interval,
+            None if self.brightness_changed => FADE_INTERVAL,
+            _ => return None,
+        };
+        Some(self.last_frame.map_or_else(Instant::now, |last| last + interval))
+    }
+
+    /// Notes that something changed, sending it right away unless batching.
+    fn changed(&mut self) {
+        if self.frame_interval.is_some() {
+            self.pending_since.get_or_insert_with(Instant::now);
+        } else {
+            self.send_changes(Instant::now());
+        }
+    }
+
+    /// Forgets what the device shows, for when it's anyone's guess.
+    fn forget_sent(&mut self) {
+        self.sent_pads = [None; PAD_COUNT];
+        self.sent_cc_leds = [None; 128];
+    }
+
+    /// Marks what was sent on this connection for sending again with the
+    /// current calibration and brightness.  The rest of what the device
+    /// shows isn't ours to touch.
+    fn mark_sent_dirty(&mut self) {
+        for i in 0..PAD_COUNT {
+            self.dirty_pads[i] |= self.sent_pads[i].is_some();
+        }
+        for cc in 0..128 {
+            self.dirty_cc_leds[cc] |= self.sent_cc_leds[cc].is_some();
+        }
+    }
+
+    /// Sends the dirty pads that don't show their color at the brightness of
+    /// `now` already, returning the number of bytes sent.
+    fn send_pads(&mut self, now: Instant) -> usize {
+        let levels = self.brightness.pad_levels(now);
+        let calibration = *self.calibration.lock().unwrap();
+        let colors: Vec<PadColor> = {
+            let shadow = self.shadow.lock().unwrap();
+            (0..PAD_COUNT).map(|i| {
+                let color = self.overlay_pads[i].unwrap_or_else(|| shadow.pad(i).unwrap());
+                let [r, g, b] = brightness::scale_pad(calibration.apply(color), levels[i]);
+                PadColor { index: i as u8, r, g, b }
+            }).collect()
+        };
+        let dirty: Vec<PadColor> = colors.iter().copied().filter(|pad| {
+            let i = pad.index as usize;
+            self.dirty_pads[i] && self.sent_pads[i] != Some([pad.r, pad.g, pad.b])
+        }).collect();
+        self.dirty_pads = [false; PAD_COUNT];
+        if dirty.is_empty() {
+            return 0;
+        }
+        // Past half the grid, the full frame isn't much bigger and puts every
+        // pad back in a known state.
+        let pads = if dirty.len() * 2 > PAD_COUNT { colors } else { dirty };
+        let sent = self.send_command(&ControllerCommand::PadColors(pads.clone()));
+        if sent > 0 {
+            for pad in pads {
+                self.sent_pads[pad.index as usize] = Some([pad.r, pad.g, pad.b]);
+            }
+        }
+        sent
+    }
+
+    /// Sends the dirty CC LEDs that don't show their value at the brightness
+    /// of `now` already, returning the number of bytes sent.
+    fn send_cc_leds(&mut self, now: Instant) -> usize {
+        let level = self.brightness.button_level(now);
+        let mut bytes = 0;
+        for cc in 0..128u8 {
+            if !std::mem::replace(&mut self.dirty_cc_leds[cc as usize], false) {
+                continue;
+            }
+            let shown = self.overlay_cc_leds[cc as usize]
+                .or_else(|| self.shadow.lock().unwrap().cc_led(cc));
+            let value = match shown {
+                Some(value) => output::dim_cc_led(cc, value, level),
+                // Only an overlay gets LEDs the shadow state never set lit.
+                None if self.sent_cc_leds[cc as usize].is_some() => output::cc_led_off(cc),
+                None => continue,
+            };
+            if self.sent_cc_leds[cc as usize] != Some(value) {
+                let sent = self.send_command(&ControllerCommand::ButtonLed { cc, value });
+                if sent > 0 {
+                    self.sent_cc_leds[cc as usize] = Some(value);
+                }
+                bytes += sent;
+            }
+        }
+        bytes
+    }
+
+    /// Sends everything dirty, returning the number of bytes sent.
+    fn send_changes(&mut self, now: Instant) -> usize {
+        let mut bytes = self.send_pads(now);
+        bytes += self.send_cc_leds(now);
+        if std::mem::replace(&mut self.dirty_oled, false) {
+            let command = self.shadow.lock().unwrap().oled().to_command();
+            bytes += self.send_command(&command);
+        }
+        bytes
+    }
+
+    /// Sends everything that changed since the last frame, along with the
+    /// next step of any fade.
+    fn send_frame(&mut self) {
+        let start = Instant::now();
+        if self.brightness_changed {
+            self.mark_sent_dirty();
+            // Once settled, this frame shows the final levels.
+            if !self.brightness.is_fading(start) {
+                self.brightness_changed = false;
+                self.brightness.prune(start);
+            }
+        }
+        let bytes = self.send_changes(start);
+
+        let latency = self.pending_since.take().map_or(Duration::from_secs(0), |since| start - since);
+        // Fade steps sent with the scheduler off aren't frames.
+        if self.frame_interval.is_some() {
+            self.stats.lock().unwrap().record(bytes, latency, start.elapsed());
+        }
+        self.last_frame = Some(start);
+    }
+
+    /// Sends a command the writer put together, returning the number of
+    /// bytes sent.
+    fn send_command(&mut self, command: &ControllerCommand) -> usize {
+        let mut buf = std::mem::take(&mut self.buf);
+        let mut sent = 0;
+        // The commands are put together from valid ones.
+        if command.encode(&mut buf).is_ok() && self.send(&buf) {
+            sent = buf.len();
+        }
+        self.buf = buf;
+        sent
+    }
+
+    /// Sends the bytes if there's a connection, returning whether they were
+    /// sent.
+    fn send(&mut self, bytes: &[u8]) -> bool {
+        let result = match &mut self.connection {
+            Some(connection) => connection.send(bytes),
+            None => return false,
+        };
+        if let Err(e) = result {
+            self.connection = None;
+            self.forget_sent();
+            self.error.get_or_insert(e);
+            // The controller may have noticed first.
+            if self.connected.swap(false, Ordering::SeqCst) {
+                (self.on_lost)();
+            }
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::DiscoveryOptions;
+    use crate::mock::TestFire;
+
+    fn attach() -> TestFire {
+        TestFire::attach(&DiscoveryOptions::default())
+    }
+
+    fn lengths(sent: Vec<Vec<u8>>) -> Vec<usize> {
+        sent.iter().map(|message| message.len()).collect()
+    }
+
+    /// The pad sysex for `count` pads.
+    fn pads_len(count: usize) -> usize {
+        8 + 4 * count
+    }
+
+    #[test]
+    fn pad_index_past_the_grid_is_rejected() {
+        let fire = attach();
+        let command = ControllerCommand::PadColors(vec![PadColor { index: 100, r: 1, g: 2, b: 3 }]);
+        assert!(matches!(fire.controller.send_command(&command),
+                         Err(FireError::InvalidArgument(_))));
+
+        // The writer is still there.
+        fire.controller.output().set_pad(3, 1, 2, 3).unwrap();
+        assert_eq!(fire.sent(), vec![vec![0xf0, 0x47, 0x7f, 0x43, 0x65, 0x00, 0x04, 3, 1, 2, 3, 0xf7]]);
+    }
+
+    #[test]
+    fn only_changed_pads_are_sent() {
+        let mut fire = attach();
+        fire.controller.update_leds().unwrap();
+        // Nothing is known to be on the device yet.
+        assert_eq!(lengths(fire.sent()), vec![pads_len(PAD_COUNT)]);
+
+        fire.controller.update_leds().unwrap();
+        assert!(fire.sent().is_empty());
+
+        fire.controller.set_led(5, 1, 2, 3).unwrap();
+        fire.controller.set_led(9, 1, 2, 3).unwrap();
+        fire.controller.update_leds().unwrap();
+        assert_eq!(fire.sent(), vec![vec![0xf0, 0x47, 0x7f, 0x43, 0x65, 0x00, 0x08,
+                                          5, 1, 2, 3, 9, 1, 2, 3, 0xf7]]);
+    }
+
+    #[test]
+    fn mostly_changed_grid_is_sent_whole() {
+        let mut fire = attach();
+        fire.controller.update_leds().unwrap();
+        fire.sent();
+        fire.controller.set_color_cube();
+        fire.controller.update_leds().unwrap();
+        assert_eq!(lengths(fire.sent()), vec![pads_len(PAD_COUNT)]);
+    }
+
+    #[test]
+    fn unknown_state_is_sent_again() {
+        let fire = attach();
+        let handle = fire.controller.output();
+        handle.set_pad(0, 1, 1, 1).unwrap();
+        handle.set_cc_led(0x33, 1).unwrap();
+        fire.sent();
+
+        handle.resync().unwrap();
+        assert_eq!(lengths(fire.sent()), vec![pads_len(PAD_COUNT), 3, 1188]);
+
+        // The same colors again are known to be there.
+        handle.set_pad(0, 1, 1, 1).unwrap();
+        handle.set_cc_led(0x33, 1).unwrap();
+        assert!(fire.sent().is_empty());
+    }
+
+    #[test]
+    fn frames_batch_changes() {
+        let fire = attach();
+        let handle = fire.controller.output();
+        handle.set_frame_interval(Some(Duration::from_secs(3600))).unwrap();
+        // The first change after a quiet spell goes right out.
+        handle.set_pad(0, 1, 1, 1).unwrap();
+        assert_eq!(lengths(fire.sent()), vec![pads_len(1)]);
+        for i in 1..10 {
+            handle.set_pad(i, 1, 1, 1).unwrap();
+            handle.set_cc_led(0x33, i % 2).unwrap();
+        }
+        handle.present().unwrap();
+        assert_eq!(lengths(fire.sent()), vec![pads_len(9), 3]);
+        assert_eq!(handle.frame_stats().frames, 2);
+    }
+
+    #[test]
+    fn fades_without_frames_leave_the_stats_alone() {
+        let fire = attach();
+        let handle = fire.controller.output();
+        handle.set_pad(0, 0x7f, 0x7f, 0x7f).unwrap();
+        handle.set_brightness(0.5, Duration::from_millis(100)).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(fire.sent().last(), Some(&vec![0xf0, 0x47, 0x7f, 0x43, 0x65, 0x00, 0x04,
+                                                  0, 64, 64, 64, 0xf7]));
+        assert_eq!(handle.frame_stats(), FrameStats::default());
+    }
+
+    #[test]
+    fn shadow_reads_back_what_was_just_set() {
+        let fire = attach();
+        let output = fire.controller.output();
+        for i in 0..200 {
+            let state = if i % 2 == 0 {
+                ButtonLedState::BrightGreen
+            } else {
+                ButtonLedState::DullYellow
+            };
+            output.set_button_led(ControllerButton::Play, state).unwrap();
+            assert_eq!(output.shadow().button_led(ControllerButton::Play), Some(state));
+        }
+    }
+
+    #[test]
+    fn overlay_leaves_the_shadow_alone() {
+        let fire = attach();
+        let output = fire.controller.output();
+        output.set_pad(0, 0x7f, 0, 0).unwrap();
+        fire.sent();
+
+        let blue = PadColor { index: 0, r: 0, g: 0, b: 0x7f };
+        output.overlay(&ControllerCommand::PadColors(vec![blue])).unwrap();
+        output.overlay(&output::track_led_command(0, ButtonLedState::BrightGreen).unwrap())
+            .unwrap();
+        // Drawing underneath the overlay only shows where it isn't.
+        output.set_pad(0, 0, 0x7f, 0).unwrap();
+        output.set_pad(1, 0, 0x7f, 0).unwrap();
+        assert_eq!(fire.sent(), vec![
+            vec![0xf0, 0x47, 0x7f, 0x43, 0x65, 0x00, 0x04, 0, 0, 0, 0x7f, 0xf7],
+            vec![0xb0, 0x28, 0x04],
+            vec![0xf0, 0x47, 0x7f, 0x43, 0x65, 0x00, 0x04, 1, 0, 0x7f, 0, 0xf7],
+        ]);
+        let shadow = output.shadow();
+        assert_eq!(shadow.pad(0), Some([0, 0x7f, 0]));
+        assert_eq!(shadow.track_led(0), None);
+
+        output.clear_overlay().unwrap();
+        assert_eq!(fire.sent(), vec![
+            vec![0xf0, 0x47, 0x7f, 0x43, 0x65, 0x00, 0x04, 0, 0, 0x7f, 0, 0xf7],
+            vec![0xb0, 0x28, 0x00],
+        ]);
+        assert_eq!(output.shadow().track_led(0), None);
+    }
+}